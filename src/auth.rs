@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{Filter, Rejection};
+
+use crate::config::Config;
+use crate::errors::{
+    ChainNotAllowedError, RouteNotAllowedError, StateNotFound, TooManySessionsError,
+    UnauthorizedError,
+};
+use crate::limits::Permit;
+
+pub const API_KEY_HEADER: &str = "X-API-KEY";
+
+/// An API key allowed to call Temper, along with the restrictions applying to it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub key: String,
+    /// Identifies whoever uses this key in logs, never the key itself.
+    pub label: String,
+    /// Chains this key may simulate on, all chains if not set.
+    pub chains: Option<Vec<u64>>,
+    /// Routes this key may call, all routes if not set.
+    pub routes: Option<Vec<Route>>,
+    /// Maximum number of requests per second, unlimited if not set.
+    pub rate_limit: Option<u32>,
+    /// Maximum number of concurrent stateful simulations, unlimited if not set.
+    pub max_sessions: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Route {
+    Simulate,
+    SimulateBundle,
+    SimulateStateful,
+    Simulations,
+    Replay,
+}
+
+/// Whoever is making a request, `key` is `None` if authentication is disabled. Requests running
+/// simulations hold their `permit` through their client until they're handled.
+#[derive(Debug, Clone)]
+pub struct Client {
+    key: Option<Arc<ApiKey>>,
+    permit: Option<Arc<Permit>>,
+}
+
+impl Client {
+    pub fn is_authenticated(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// The rate limit of the API key of the client, if it has its own.
+    pub fn rate_limit(&self) -> Option<u32> {
+        self.key.as_ref().and_then(|key| key.rate_limit)
+    }
+
+    pub(crate) fn with_permit(self, permit: Permit) -> Self {
+        Client {
+            permit: Some(Arc::new(permit)),
+            ..self
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        self.key
+            .as_ref()
+            .map_or("anonymous", |key| key.label.as_str())
+    }
+
+    pub fn check_chain(&self, chain_id: u64) -> Result<(), Rejection> {
+        let allowed = self
+            .key
+            .as_ref()
+            .and_then(|key| key.chains.as_ref())
+            .map_or(true, |chains| chains.contains(&chain_id));
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(warp::reject::custom(ChainNotAllowedError))
+        }
+    }
+
+    fn check_route(&self, route: Route) -> Result<(), Rejection> {
+        let allowed = self
+            .key
+            .as_ref()
+            .and_then(|key| key.routes.as_ref())
+            .map_or(true, |routes| routes.contains(&route));
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(warp::reject::custom(RouteNotAllowedError))
+        }
+    }
+}
+
+pub struct Auth {
+    keys: HashMap<String, Arc<ApiKey>>,
+    /// The label of the client owning each stateful simulation.
+    sessions: DashMap<Uuid, String>,
+}
+
+impl Auth {
+    pub fn new(config: &Config) -> Self {
+        let mut keys: HashMap<String, Arc<ApiKey>> = config
+            .api_keys
+            .iter()
+            .map(|key| (key.key.clone(), Arc::new(key.clone())))
+            .collect();
+
+        // The single key set through `API_KEY` isn't restricted in any way.
+        if let Some(api_key) = &config.api_key {
+            keys.insert(
+                api_key.clone(),
+                Arc::new(ApiKey {
+                    key: api_key.clone(),
+                    label: "default".to_string(),
+                    chains: None,
+                    routes: None,
+                    rate_limit: None,
+                    max_sessions: None,
+                }),
+            );
+        }
+
+        Auth {
+            keys,
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// The API key `key` refers to, if it's a known one.
+    pub fn api_key(&self, key: Option<&str>) -> Option<&ApiKey> {
+        key.and_then(|key| self.keys.get(key))
+            .map(|key| key.as_ref())
+    }
+
+    /// The label of the client using `key`, for logging.
+    pub fn label(&self, key: Option<&str>) -> &str {
+        match key.and_then(|key| self.keys.get(key)) {
+            Some(key) => key.label.as_str(),
+            None if self.is_enabled() => "unauthenticated",
+            None => "anonymous",
+        }
+    }
+
+    pub fn authorize(&self, key: Option<String>, route: Route) -> Result<Client, Rejection> {
+        if !self.is_enabled() {
+            return Ok(Client {
+                key: None,
+                permit: None,
+            });
+        }
+
+        let key = key
+            .and_then(|key| self.keys.get(&key).cloned())
+            .ok_or_else(|| warp::reject::custom(UnauthorizedError))?;
+
+        let client = Client {
+            key: Some(key),
+            permit: None,
+        };
+        client.check_route(route)?;
+
+        Ok(client)
+    }
+
+    /// Records `id` as a stateful simulation owned by `client`, if it's allowed another one.
+    pub fn start_session(&self, client: &Client, id: Uuid) -> Result<(), Rejection> {
+        if let Some(max_sessions) = client.key.as_ref().and_then(|key| key.max_sessions) {
+            let sessions = self
+                .sessions
+                .iter()
+                .filter(|session| session.value() == client.label())
+                .count();
+            if sessions >= max_sessions {
+                return Err(warp::reject::custom(TooManySessionsError));
+            }
+        }
+
+        self.sessions.insert(id, client.label().to_string());
+        Ok(())
+    }
+
+    /// Checks `client` owns the stateful simulation `id`, clients can't see each other's.
+    pub fn check_session(&self, client: &Client, id: Uuid) -> Result<(), Rejection> {
+        match self.sessions.get(&id) {
+            Some(owner) if owner.value() == client.label() => Ok(()),
+            _ => Err(warp::reject::custom(StateNotFound())),
+        }
+    }
+
+    /// Records `id` as a stateful simulation owned by the client labelled `owner`, regardless of
+    /// its limits, for simulations restored at startup.
+    pub fn restore_session(&self, id: Uuid, owner: String) {
+        self.sessions.insert(id, owner);
+    }
+
+    pub fn end_session(&self, id: Uuid) {
+        self.sessions.remove(&id);
+    }
+}
+
+/// Authenticates the request through its `X-API-KEY` header and checks it may call `route`.
+pub fn with_client(
+    auth: Arc<Auth>,
+    route: Route,
+) -> impl Filter<Extract = (Client,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(API_KEY_HEADER).and_then(move |key: Option<String>| {
+        let auth = auth.clone();
+        async move { auth.authorize(key, route) }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApiKey, Auth, Route};
+    use crate::config::config;
+
+    fn key(label: &str) -> ApiKey {
+        ApiKey {
+            key: format!("{label}-key"),
+            label: label.to_string(),
+            chains: None,
+            routes: None,
+            rate_limit: None,
+            max_sessions: None,
+        }
+    }
+
+    #[test]
+    fn test_auth_disabled() {
+        let auth = Auth::new(&crate::config::Config {
+            api_key: None,
+            api_keys: vec![],
+            ..config()
+        });
+
+        let client = auth.authorize(None, Route::Simulate).unwrap();
+        assert_eq!(client.label(), "anonymous");
+    }
+
+    #[test]
+    fn test_auth_keys() {
+        let auth = Auth::new(&crate::config::Config {
+            api_key: Some("secret".to_string()),
+            api_keys: vec![ApiKey {
+                chains: Some(vec![1]),
+                routes: Some(vec![Route::Simulate]),
+                ..key("team")
+            }],
+            ..config()
+        });
+
+        assert!(auth.authorize(None, Route::Simulate).is_err());
+        assert!(auth
+            .authorize(Some("wrong".to_string()), Route::Simulate)
+            .is_err());
+
+        let client = auth
+            .authorize(Some("secret".to_string()), Route::SimulateStateful)
+            .unwrap();
+        assert_eq!(client.label(), "default");
+        assert!(client.check_chain(137).is_ok());
+
+        let client = auth
+            .authorize(Some("team-key".to_string()), Route::Simulate)
+            .unwrap();
+        assert_eq!(client.label(), "team");
+        assert!(client.check_chain(1).is_ok());
+        assert!(client.check_chain(137).is_err());
+        assert!(auth
+            .authorize(Some("team-key".to_string()), Route::SimulateBundle)
+            .is_err());
+    }
+
+    #[test]
+    fn test_auth_sessions() {
+        let auth = Auth::new(&crate::config::Config {
+            api_keys: vec![
+                ApiKey {
+                    max_sessions: Some(1),
+                    ..key("team")
+                },
+                key("other"),
+            ],
+            ..config()
+        });
+
+        let team = auth
+            .authorize(Some("team-key".to_string()), Route::SimulateStateful)
+            .unwrap();
+        let other = auth
+            .authorize(Some("other-key".to_string()), Route::SimulateStateful)
+            .unwrap();
+
+        let id = uuid::Uuid::new_v4();
+        assert!(auth.start_session(&team, id).is_ok());
+        assert!(auth.start_session(&team, uuid::Uuid::new_v4()).is_err());
+        assert!(auth.check_session(&team, id).is_ok());
+        assert!(auth.check_session(&other, id).is_err());
+
+        auth.end_session(id);
+        assert!(auth.start_session(&team, uuid::Uuid::new_v4()).is_ok());
+    }
+}
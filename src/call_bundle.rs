@@ -0,0 +1,503 @@
+use std::time::Duration;
+
+use ethers::abi::{decode, ParamType};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Address, Block, BlockNumber, Bytes, Transaction, TxHash, H256, U256, U64};
+use ethers::utils::keccak256;
+use ethers::utils::rlp::{Decodable, Rlp};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::auth::Client;
+use crate::config::Config;
+use crate::errors::{
+    BlockNotFoundError, EvmError, ForkError, IncorrectChainIdError, NoURLForChainIdError,
+};
+use crate::evm::Evm;
+use crate::hardfork::Schedule;
+use crate::replay::{apply, rpc};
+use crate::simulation::PermissiveUint;
+
+/// The selector of `Error(string)`, which `revert` and `require` revert with.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcResponse<T> {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl JsonRpcError {
+    fn method_not_found(method: &str) -> Self {
+        JsonRpcError {
+            code: -32601,
+            message: format!("the method {method} does not exist/is not available"),
+        }
+    }
+
+    fn invalid_params(message: impl std::fmt::Display) -> Self {
+        JsonRpcError {
+            code: -32602,
+            message: format!("invalid params: {message}"),
+        }
+    }
+
+    /// A transaction of the bundle which can't be included, as geth reports it.
+    fn server_error(message: impl std::fmt::Display) -> Self {
+        JsonRpcError {
+            code: -32000,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The parameters of `eth_callBundle`, as defined by Flashbots. Unknown ones, such as `timeout`,
+/// are rejected rather than ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CallBundleRequest {
+    /// Signed transactions, in order.
+    pub txs: Vec<Bytes>,
+    /// The block the bundle is meant to be included in.
+    pub block_number: U64,
+    /// The block whose state the bundle runs on, usually `latest`.
+    pub state_block_number: BlockNumber,
+    /// The timestamp of the block the bundle runs in, 12 seconds after the state block if not
+    /// set.
+    pub timestamp: Option<u64>,
+    /// The coinbase of the block, the miner of the state block if not set.
+    pub coinbase: Option<Address>,
+    /// The gas limit of the block, that of the state block if not set.
+    pub gas_limit: Option<u64>,
+    /// The difficulty of the block, that of the state block if not set.
+    pub difficulty: Option<PermissiveUint>,
+    /// The base fee of the block, following that of the state block according to EIP-1559 if
+    /// not set.
+    pub base_fee: Option<PermissiveUint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallBundleResponse {
+    pub bundle_gas_price: String,
+    pub bundle_hash: H256,
+    pub coinbase_diff: String,
+    pub eth_sent_to_coinbase: String,
+    pub gas_fees: String,
+    pub results: Vec<CallBundleResult>,
+    pub state_block_number: u64,
+    pub total_gas_used: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallBundleResult {
+    pub coinbase_diff: String,
+    pub eth_sent_to_coinbase: String,
+    pub from_address: Address,
+    pub gas_fees: String,
+    pub gas_price: String,
+    pub gas_used: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_address: Option<Address>,
+    pub tx_hash: TxHash,
+    /// The data returned by the transaction, if it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Why the transaction reverted: the reason it gave, or the data it reverted with if it
+    /// gave none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revert: Option<String>,
+}
+
+/// A JSON-RPC endpoint for searcher tooling, only supporting `eth_callBundle`.
+pub async fn rpc_handler(
+    chain_id: u64,
+    client: Client,
+    request: JsonRpcRequest,
+    config: Config,
+) -> Result<Json, Rejection> {
+    let result = match request.method.as_str() {
+        "eth_callBundle" => match serde_json::from_value::<(CallBundleRequest,)>(request.params) {
+            Ok((params,)) => call_bundle(chain_id, &client, params, &config).await?,
+            Err(err) => Err(JsonRpcError::invalid_params(err)),
+        },
+        method => Err(JsonRpcError::method_not_found(method)),
+    };
+
+    let (result, error) = match result {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    let response = JsonRpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id: request.id,
+        result,
+        error,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Runs a bundle of signed transactions on top of the state block, in the block it targets,
+/// returning what the coinbase of that block would earn from each. Invalid parameters are
+/// reported as JSON-RPC errors, and other failures rejected like other simulations.
+async fn call_bundle(
+    chain_id: u64,
+    client: &Client,
+    params: CallBundleRequest,
+    config: &Config,
+) -> Result<Result<CallBundleResponse, JsonRpcError>, Rejection> {
+    client.check_chain(chain_id)?;
+
+    let transactions = match params
+        .txs
+        .iter()
+        .map(decode_transaction)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(transactions) if !transactions.is_empty() => transactions,
+        Ok(_) => return Ok(Err(JsonRpcError::invalid_params("bundle missing txs"))),
+        Err(err) => return Ok(Err(err)),
+    };
+    // Transactions signed before EIP-155 can be replayed on any chain.
+    if let Some(transaction) = transactions
+        .iter()
+        .find(|transaction| matches!(transaction.chain_id, Some(id) if id != U256::from(chain_id)))
+    {
+        return Ok(Err(JsonRpcError::invalid_params(format!(
+            "transaction {:?} is signed for chain {}",
+            transaction.hash,
+            transaction.chain_id.unwrap_or_default()
+        ))));
+    }
+
+    let fork_url = config
+        .fork_url_for(chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
+    let provider = Provider::<Http>::try_from(fork_url.as_str())
+        .map_err(|err| warp::reject::custom(ForkError(err.into())))?;
+    let timeout = Duration::from_secs(config.fork_timeout);
+
+    let state_block = rpc(timeout, provider.get_block(params.state_block_number))
+        .await?
+        .ok_or_else(|| warp::reject::custom(BlockNotFoundError))?;
+    let state_block_number = state_block
+        .number
+        .ok_or_else(|| warp::reject::custom(BlockNotFoundError))?
+        .as_u64();
+
+    let gas_limit = params.gas_limit.map_or(state_block.gas_limit, U256::from);
+    let mut evm = Evm::new(
+        None,
+        fork_url,
+        Some(state_block_number),
+        gas_limit.as_u64(),
+        true,
+        config.fork_cache,
+        config.etherscan_key.clone(),
+        timeout,
+    )
+    .await?;
+
+    if evm.get_chain_id() != U256::from(chain_id) {
+        return Err(warp::reject::custom(IncorrectChainIdError()));
+    }
+
+    let header = Block {
+        number: Some(params.block_number),
+        timestamp: params
+            .timestamp
+            .map(U256::from)
+            .unwrap_or(state_block.timestamp + 12),
+        author: params.coinbase.or(state_block.author),
+        gas_limit,
+        difficulty: params.difficulty.map_or(state_block.difficulty, U256::from),
+        base_fee_per_gas: params.base_fee.map(U256::from).or_else(|| {
+            state_block
+                .base_fee_per_gas
+                .map(|_| next_base_fee(&state_block))
+        }),
+        ..state_block.clone()
+    };
+    evm.set_block_header(&header);
+    evm.set_schedule(Schedule::new(config, chain_id, None), None)?;
+    let coinbase = header.author.unwrap_or_default();
+    let base_fee = header.base_fee_per_gas.unwrap_or_default();
+
+    let mut results = Vec::with_capacity(transactions.len());
+    let (mut total_coinbase_diff, mut total_eth_sent_to_coinbase, mut total_gas_fees) =
+        (U256::zero(), U256::zero(), U256::zero());
+    for transaction in &transactions {
+        let (_, nonce, _) = evm.get_account(transaction.from)?;
+        if transaction.nonce != U256::from(nonce) {
+            let message = if transaction.nonce < U256::from(nonce) {
+                "nonce too low"
+            } else {
+                "nonce too high"
+            };
+            return Ok(Err(JsonRpcError::server_error(format!(
+                "{message}: address {:?}, tx: {} state: {nonce}",
+                transaction.from, transaction.nonce
+            ))));
+        }
+
+        let before = evm.get_balance(coinbase)?;
+        // Transactions the EVM refuses, for instance because their sender can't pay for their
+        // gas, can't be included.
+        let result = match apply(&mut evm, transaction, false).await {
+            Ok(result) => result,
+            Err(rejection) => match rejection.find::<EvmError>() {
+                Some(EvmError(err)) => {
+                    return Ok(Err(JsonRpcError::server_error(format!(
+                        "err: {err}; txhash {:?}",
+                        transaction.hash
+                    ))))
+                }
+                None => return Err(rejection),
+            },
+        };
+        let after = evm.get_balance(coinbase)?;
+
+        // The transaction pays its fees, so what the coinbase earns beyond its tips was sent to
+        // it directly.
+        let coinbase_diff = after.saturating_sub(before);
+        let gas_fees = U256::from(result.gas_used) * effective_tip(transaction, base_fee);
+        let eth_sent_to_coinbase = coinbase_diff.saturating_sub(gas_fees);
+        total_coinbase_diff += coinbase_diff;
+        total_eth_sent_to_coinbase += eth_sent_to_coinbase;
+        total_gas_fees += gas_fees;
+
+        results.push(CallBundleResult {
+            coinbase_diff: coinbase_diff.to_string(),
+            eth_sent_to_coinbase: eth_sent_to_coinbase.to_string(),
+            from_address: transaction.from,
+            gas_fees: gas_fees.to_string(),
+            gas_price: (coinbase_diff / result.gas_used.max(1)).to_string(),
+            gas_used: result.gas_used,
+            to_address: transaction.to,
+            tx_hash: transaction.hash,
+            error: (!result.success).then(|| "execution reverted".to_string()),
+            revert: (!result.success && !result.return_data.is_empty())
+                .then(|| revert_reason(&result.return_data)),
+            value: result.success.then_some(result.return_data),
+        });
+    }
+
+    let total_gas_used: u64 = results.iter().map(|result| result.gas_used).sum();
+
+    Ok(Ok(CallBundleResponse {
+        bundle_gas_price: (total_coinbase_diff / total_gas_used.max(1)).to_string(),
+        bundle_hash: bundle_hash(&transactions),
+        coinbase_diff: total_coinbase_diff.to_string(),
+        eth_sent_to_coinbase: total_eth_sent_to_coinbase.to_string(),
+        gas_fees: total_gas_fees.to_string(),
+        results,
+        state_block_number,
+        total_gas_used,
+    }))
+}
+
+/// Decodes a signed transaction and recovers its sender.
+fn decode_transaction(raw: &Bytes) -> Result<Transaction, JsonRpcError> {
+    let mut transaction =
+        Transaction::decode(&Rlp::new(raw)).map_err(JsonRpcError::invalid_params)?;
+    transaction
+        .recover_from_mut()
+        .map_err(JsonRpcError::invalid_params)?;
+    Ok(transaction)
+}
+
+/// The reason given by `Error(string)` revert data, or the data itself in hex otherwise.
+fn revert_reason(data: &Bytes) -> String {
+    data.strip_prefix(&ERROR_SELECTOR)
+        .and_then(|encoded| decode(&[ParamType::String], encoded).ok())
+        .and_then(|tokens| tokens.into_iter().next()?.into_string())
+        .unwrap_or_else(|| data.to_string())
+}
+
+/// The hash of the concatenated hashes of the transactions of a bundle.
+fn bundle_hash(transactions: &[Transaction]) -> H256 {
+    let hashes: Vec<u8> = transactions
+        .iter()
+        .flat_map(|transaction| transaction.hash.to_fixed_bytes())
+        .collect();
+    H256(keccak256(hashes))
+}
+
+/// What the coinbase earns per unit of gas used by `transaction`, when the base fee is
+/// `base_fee`.
+fn effective_tip(transaction: &Transaction, base_fee: U256) -> U256 {
+    match (
+        transaction.max_fee_per_gas,
+        transaction.max_priority_fee_per_gas,
+    ) {
+        (Some(max_fee), Some(max_priority_fee)) => {
+            max_priority_fee.min(max_fee.saturating_sub(base_fee))
+        }
+        _ => transaction
+            .gas_price
+            .unwrap_or_default()
+            .saturating_sub(base_fee),
+    }
+}
+
+/// The base fee of the block after `parent`, as defined by EIP-1559.
+fn next_base_fee<TX>(parent: &Block<TX>) -> U256 {
+    let base_fee = parent.base_fee_per_gas.unwrap_or_default();
+    let target = parent.gas_limit / 2;
+    if target.is_zero() || parent.gas_used == target {
+        return base_fee;
+    }
+
+    if parent.gas_used > target {
+        let delta = base_fee * (parent.gas_used - target) / target / 8;
+        base_fee + delta.max(U256::one())
+    } else {
+        let delta = base_fee * (target - parent.gas_used) / target / 8;
+        base_fee.saturating_sub(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::transaction::eip2718::TypedTransaction;
+    use ethers::types::{
+        Address, Block, Eip1559TransactionRequest, TransactionRequest, TxHash, H256, U256,
+    };
+    use ethers::utils::keccak256;
+
+    use super::{bundle_hash, decode_transaction, effective_tip, next_base_fee};
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn wallet() -> LocalWallet {
+        "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap()
+            .with_chain_id(1u64)
+    }
+
+    fn sign(transaction: TypedTransaction) -> ethers::types::Bytes {
+        let signature = wallet().sign_transaction_sync(&transaction).unwrap();
+        transaction.rlp_signed(&signature)
+    }
+
+    #[test]
+    fn test_decode_transaction() {
+        let to = Address::repeat_byte(0x11);
+
+        let legacy = sign(
+            TransactionRequest::new()
+                .to(to)
+                .value(1)
+                .gas(21_000)
+                .gas_price(2 * GWEI)
+                .nonce(0)
+                .chain_id(1)
+                .into(),
+        );
+        let transaction = decode_transaction(&legacy).unwrap();
+        assert_eq!(transaction.from, wallet().address());
+        assert_eq!(transaction.to, Some(to));
+        assert_eq!(transaction.hash, H256(keccak256(&legacy)));
+
+        let eip1559 = sign(
+            Eip1559TransactionRequest::new()
+                .to(to)
+                .value(1)
+                .gas(21_000)
+                .max_fee_per_gas(3 * GWEI)
+                .max_priority_fee_per_gas(GWEI)
+                .nonce(1)
+                .chain_id(1)
+                .into(),
+        );
+        let transaction = decode_transaction(&eip1559).unwrap();
+        assert_eq!(transaction.from, wallet().address());
+        assert_eq!(transaction.max_priority_fee_per_gas, Some(GWEI.into()));
+
+        assert!(decode_transaction(&vec![0x02, 0x01].into()).is_err());
+    }
+
+    #[test]
+    fn test_effective_tip() {
+        let base_fee = U256::from(2 * GWEI);
+
+        let legacy = ethers::types::Transaction {
+            gas_price: Some((3 * GWEI).into()),
+            ..Default::default()
+        };
+        assert_eq!(effective_tip(&legacy, base_fee), GWEI.into());
+
+        let eip1559 = ethers::types::Transaction {
+            max_fee_per_gas: Some((5 * GWEI).into()),
+            max_priority_fee_per_gas: Some(GWEI.into()),
+            ..Default::default()
+        };
+        assert_eq!(effective_tip(&eip1559, base_fee), GWEI.into());
+
+        let capped = ethers::types::Transaction {
+            max_fee_per_gas: Some((2 * GWEI + 1).into()),
+            max_priority_fee_per_gas: Some(GWEI.into()),
+            ..Default::default()
+        };
+        assert_eq!(effective_tip(&capped, base_fee), 1.into());
+    }
+
+    #[test]
+    fn test_next_base_fee() {
+        let parent = |gas_used: u64| Block::<TxHash> {
+            gas_limit: 30_000_000.into(),
+            gas_used: gas_used.into(),
+            base_fee_per_gas: Some((10 * GWEI).into()),
+            ..Default::default()
+        };
+
+        assert_eq!(next_base_fee(&parent(15_000_000)), (10 * GWEI).into());
+        assert_eq!(
+            next_base_fee(&parent(30_000_000)),
+            (11_250_000_000u64).into()
+        );
+        assert_eq!(next_base_fee(&parent(0)), (8_750_000_000u64).into());
+    }
+
+    #[test]
+    fn test_bundle_hash() {
+        let transactions: Vec<ethers::types::Transaction> = [0x11, 0x22]
+            .into_iter()
+            .map(|byte| ethers::types::Transaction {
+                hash: H256::repeat_byte(byte),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut hashes = [0x11; 64];
+        hashes[32..].fill(0x22);
+        assert_eq!(bundle_hash(&transactions), H256(keccak256(hashes)));
+    }
+}
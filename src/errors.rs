@@ -2,12 +2,17 @@ use eyre::Report;
 use serde::{Deserialize, Serialize};
 use std::{convert::Infallible, error::Error};
 
-use warp::{body::BodyDeserializeError, hyper::StatusCode, reject::Reject, Rejection, Reply};
+use warp::{
+    body::BodyDeserializeError, http::header::RETRY_AFTER, hyper::StatusCode, reject::Reject,
+    reply::Response, Rejection, Reply,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ErrorMessage {
     pub code: u16,
     pub message: String,
+    #[serde(rename = "requestId", default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug)]
@@ -40,26 +45,184 @@ pub struct StateNotFound();
 
 impl Reject for StateNotFound {}
 
+#[derive(Debug)]
+pub struct SimulationNotFoundError;
+
+impl Reject for SimulationNotFoundError {}
+
+#[derive(Debug)]
+pub struct TransactionNotFoundError;
+
+impl Reject for TransactionNotFoundError {}
+
+#[derive(Debug)]
+pub struct BlockNotFoundError;
+
+impl Reject for BlockNotFoundError {}
+
+#[derive(Debug)]
+pub struct InvalidTransactionIndexError;
+
+impl Reject for InvalidTransactionIndexError {}
+
+#[derive(Debug)]
+pub struct TransactionPendingError;
+
+impl Reject for TransactionPendingError {}
+
+#[derive(Debug)]
+pub struct ContractCreationNotSupportedError;
+
+impl Reject for ContractCreationNotSupportedError {}
+
+#[derive(Debug)]
+pub struct TokenSlotNotFoundError;
+
+impl Reject for TokenSlotNotFoundError {}
+
+#[derive(Debug)]
+pub struct InvalidStorageSlotError;
+
+impl Reject for InvalidStorageSlotError {}
+
+#[derive(Debug)]
+pub struct PrecompileOverrideError;
+
+impl Reject for PrecompileOverrideError {}
+
+#[derive(Debug)]
+pub struct InvalidSafeError;
+
+impl Reject for InvalidSafeError {}
+
+#[derive(Debug)]
+pub struct InvalidEntryPointError;
+
+impl Reject for InvalidEntryPointError {}
+
+#[derive(Debug)]
+pub struct InvalidPermitError;
+
+impl Reject for InvalidPermitError {}
+
+#[derive(Debug)]
+pub struct UnsupportedHardforkError;
+
+impl Reject for UnsupportedHardforkError {}
+
+#[derive(Debug)]
+pub struct BlobTransactionError;
+
+impl Reject for BlobTransactionError {}
+
 #[derive(Debug)]
 pub struct OverrideError;
 
 impl Reject for OverrideError {}
 
+#[derive(Debug)]
+pub struct UnauthorizedError;
+
+impl Reject for UnauthorizedError {}
+
+#[derive(Debug)]
+pub struct RouteNotAllowedError;
+
+impl Reject for RouteNotAllowedError {}
+
+#[derive(Debug)]
+pub struct ChainNotAllowedError;
+
+impl Reject for ChainNotAllowedError {}
+
+#[derive(Debug)]
+pub struct RateLimitedError(pub u64);
+
+impl Reject for RateLimitedError {}
+
+#[derive(Debug)]
+pub struct TooManySimulationsError(pub u64);
+
+impl Reject for TooManySimulationsError {}
+
+#[derive(Debug)]
+pub struct TooManySessionsError;
+
+impl Reject for TooManySessionsError {}
+
 #[derive(Debug)]
 pub struct EvmError(pub Report);
 
 impl Reject for EvmError {}
 
+#[derive(Debug)]
+pub struct ForkError(pub Report);
+
+impl Reject for ForkError {}
+
+#[derive(Debug)]
+pub struct ForkTimeoutError;
+
+impl Reject for ForkTimeoutError {}
+
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    Ok(rejection_reply(&err, None))
+}
+
+/// The response to a rejected request, including its id if it has one.
+pub fn rejection_reply(err: &Rejection, request_id: Option<String>) -> Response {
     let code;
     let message: String;
-    println!("Handling rejection: {:?}", err);
+    let mut retry_after = None;
+    tracing::debug!(target: "ts::api", ?err, "handling rejection");
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND".to_string();
     } else if let Some(_e) = err.find::<StateNotFound>() {
         code = StatusCode::NOT_FOUND;
         message = "STATE_NOT_FOUND".to_string();
+    } else if let Some(SimulationNotFoundError) = err.find() {
+        code = StatusCode::NOT_FOUND;
+        message = "SIMULATION_NOT_FOUND".to_string();
+    } else if let Some(TransactionNotFoundError) = err.find() {
+        code = StatusCode::NOT_FOUND;
+        message = "TRANSACTION_NOT_FOUND".to_string();
+    } else if let Some(BlockNotFoundError) = err.find() {
+        code = StatusCode::NOT_FOUND;
+        message = "BLOCK_NOT_FOUND".to_string();
+    } else if let Some(InvalidTransactionIndexError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_TRANSACTION_INDEX".to_string();
+    } else if let Some(TransactionPendingError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "TRANSACTION_PENDING".to_string();
+    } else if let Some(ContractCreationNotSupportedError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "CONTRACT_CREATION_NOT_SUPPORTED".to_string();
+    } else if let Some(TokenSlotNotFoundError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "TOKEN_SLOT_NOT_FOUND".to_string();
+    } else if let Some(InvalidStorageSlotError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_STORAGE_SLOT".to_string();
+    } else if let Some(PrecompileOverrideError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "PRECOMPILE_OVERRIDE_NOT_SUPPORTED".to_string();
+    } else if let Some(InvalidSafeError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_SAFE".to_string();
+    } else if let Some(InvalidEntryPointError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_ENTRY_POINT".to_string();
+    } else if let Some(InvalidPermitError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "INVALID_PERMIT".to_string();
+    } else if let Some(UnsupportedHardforkError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "HARDFORK_NOT_SUPPORTED".to_string();
+    } else if let Some(BlobTransactionError) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = "BLOB_TRANSACTIONS_NOT_SUPPORTED".to_string();
     } else if let Some(NoURLForChainIdError) = err.find() {
         code = StatusCode::BAD_REQUEST;
         message = "CHAIN_ID_NOT_SUPPORTED".to_string();
@@ -78,6 +241,32 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     } else if let Some(_e) = err.find::<OverrideError>() {
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "OVERRIDE_ERROR".to_string();
+    } else if let Some(_e) = err.find::<UnauthorizedError>() {
+        code = StatusCode::UNAUTHORIZED;
+        message = "UNAUTHORIZED".to_string();
+    } else if let Some(_e) = err.find::<RouteNotAllowedError>() {
+        code = StatusCode::FORBIDDEN;
+        message = "ROUTE_NOT_ALLOWED".to_string();
+    } else if let Some(_e) = err.find::<ChainNotAllowedError>() {
+        code = StatusCode::FORBIDDEN;
+        message = "CHAIN_NOT_ALLOWED".to_string();
+    } else if let Some(RateLimitedError(seconds)) = err.find() {
+        code = StatusCode::TOO_MANY_REQUESTS;
+        message = "RATE_LIMITED".to_string();
+        retry_after = Some(*seconds);
+    } else if let Some(TooManySimulationsError(seconds)) = err.find() {
+        code = StatusCode::TOO_MANY_REQUESTS;
+        message = "TOO_MANY_SIMULATIONS".to_string();
+        retry_after = Some(*seconds);
+    } else if let Some(_e) = err.find::<TooManySessionsError>() {
+        code = StatusCode::TOO_MANY_REQUESTS;
+        message = "TOO_MANY_SESSIONS".to_string();
+    } else if let Some(_e) = err.find::<ForkTimeoutError>() {
+        code = StatusCode::GATEWAY_TIMEOUT;
+        message = "FORK_TIMEOUT".to_string();
+    } else if let Some(_e) = err.find::<ForkError>() {
+        code = StatusCode::BAD_GATEWAY;
+        message = "FORK_ERROR".to_string();
     } else if let Some(_e) = err.find::<EvmError>() {
         if _e.0.to_string().contains("CallGasCostMoreThanGasLimit") {
             code = StatusCode::BAD_REQUEST;
@@ -89,7 +278,7 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     } else if let Some(e) = err.find::<BodyDeserializeError>() {
         // This error happens if the body could not be deserialized correctly
         // We can use the cause to analyze the error and customize the error message
-        dbg!(e);
+        tracing::debug!(target: "ts::api", err = ?e, "invalid request body");
         message = match e.source() {
             Some(cause) => format!("BAD REQUEST: {cause}"),
             None => "BAD_REQUEST".to_string(),
@@ -100,12 +289,15 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
         // and render it however we want
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED".to_string();
+    } else if err.find::<warp::cors::CorsForbidden>().is_some() {
+        code = StatusCode::FORBIDDEN;
+        message = "CORS_FORBIDDEN".to_string();
     } else if err.find::<warp::reject::MissingHeader>().is_some() {
         code = StatusCode::UNAUTHORIZED;
         message = "UNAUTHORIZED".to_string();
     } else {
         // We should have expected this... Just log and say its a 500
-        eprintln!("unhandled rejection: {err:?}");
+        tracing::error!(target: "ts::api", ?err, "unhandled rejection");
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "UNHANDLED_REJECTION".to_string();
     }
@@ -113,7 +305,12 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     let json = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
         message,
+        request_id,
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let reply = warp::reply::with_status(json, code);
+    match retry_after {
+        Some(seconds) => warp::reply::with_header(reply, RETRY_AFTER, seconds).into_response(),
+        None => reply.into_response(),
+    }
 }
@@ -1,23 +1,41 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use ethers::abi::{Address, Hash, Uint};
 use ethers::core::types::Log;
 use ethers::types::transaction::eip2930::AccessList;
-use ethers::types::Bytes;
+use ethers::types::{Block, Bytes, Transaction};
+use ethers::utils::keccak256;
 use foundry_config::Chain;
+use foundry_evm::executor::backend::DatabaseExt;
+use foundry_evm::executor::inspector::cheatcodes::{
+    Cheatcodes, MockCallDataContext, MockCallReturnData,
+};
 use foundry_evm::executor::{fork::CreateFork, Executor};
-use foundry_evm::executor::{opts::EvmOpts, Backend, ExecutorBuilder};
+use foundry_evm::executor::{opts::EvmOpts, Backend, ExecutorBuilder, DEFAULT_CREATE2_DEPLOYER};
 use foundry_evm::trace::identifier::{EtherscanIdentifier, SignaturesIdentifier};
 use foundry_evm::trace::node::CallTraceNode;
-use foundry_evm::trace::{CallTraceArena, CallTraceDecoder, CallTraceDecoderBuilder};
-use foundry_evm::utils::{h160_to_b160, u256_to_ru256};
+use foundry_evm::trace::{
+    CallTraceArena, CallTraceDecoder, CallTraceDecoderBuilder, RawOrDecodedReturnData,
+};
+use foundry_evm::utils::{b160_to_h160, h160_to_b160, h256_to_b256, ru256_to_u256, u256_to_ru256};
+use foundry_evm::{CallKind, CALLER, TEST_CONTRACT_ADDRESS};
 use revm::db::DatabaseRef;
 use revm::interpreter::InstructionResult;
-use revm::primitives::{Account, Bytecode, Env, StorageSlot};
+use revm::precompile::{Precompiles, SpecId as PrecompileSpecId};
+use revm::primitives::{
+    Account, Bytecode, CreateScheme, Env, State, StorageSlot, TransactTo, B256, KECCAK_EMPTY,
+};
 use revm::DatabaseCommit;
+use tracing::Instrument;
+use warp::Rejection;
 
-use crate::errors::{EvmError, OverrideError};
-use crate::simulation::CallTrace;
+use crate::errors::{EvmError, ForkError, ForkTimeoutError, OverrideError};
+use crate::hardfork::{Hardfork, Schedule};
+use crate::metrics;
+use crate::mocks::Mock;
+use crate::relay::RelayedFork;
+use crate::simulation::{AccountDiff, CallTrace, Change, StateDiff};
 
 #[derive(Debug, Clone)]
 pub struct CallRawRequest {
@@ -39,6 +57,24 @@ pub struct CallRawResult {
     pub exit_reason: InstructionResult,
     pub return_data: Bytes,
     pub formatted_trace: Option<String>,
+    pub hardfork: Hardfork,
+    /// The changes the call made to the state, only known for calls which aren't committed.
+    pub state_diff: Option<StateDiff>,
+}
+
+impl CallRawResult {
+    /// What the first call of `kind` to `to` made during the call returned, or reverted with.
+    pub fn output_of(&self, kind: CallKind, to: Address) -> Option<Bytes> {
+        self.trace
+            .as_ref()?
+            .arena
+            .iter()
+            .find(|node| node.trace.kind == kind && node.trace.address == to)
+            .and_then(|node| match &node.trace.output {
+                RawOrDecodedReturnData::Raw(output) => Some(output.clone().into()),
+                RawOrDecodedReturnData::Decoded(_) => None,
+            })
+    }
 }
 
 impl From<CallTraceNode> for CallTrace {
@@ -62,17 +98,71 @@ pub struct Evm {
     executor: Executor,
     decoder: CallTraceDecoder,
     etherscan_identifier: Option<EtherscanIdentifier>,
+    hardfork: Hardfork,
+    schedule: Schedule,
+    /// The mocked price feeds, which answer with the block timestamp and so are mocked again
+    /// whenever it changes.
+    price_feeds: Vec<Mock>,
+    /// Relays the calls of the fork backend to the RPC node, as long as the fork is used.
+    _relay: RelayedFork,
 }
 
 impl Evm {
-    pub fn new(
+    /// Forks the chain behind `fork_url` and builds an executor on top of it.
+    ///
+    /// Setting up the fork requires several round trips to the RPC node, if those don't
+    /// complete within `fork_timeout` the setup is abandoned and a `ForkTimeoutError` returned.
+    /// Abandoning it drops its relay, which fails the calls the backend is still waiting on, so
+    /// that it gives its blocking thread back instead of waiting on a slow RPC node.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
         env: Option<Env>,
         fork_url: String,
         fork_block_number: Option<u64>,
         gas_limit: u64,
         tracing: bool,
+        fork_cache: bool,
+        etherscan_key: Option<String>,
+        fork_timeout: Duration,
+    ) -> Result<Self, Rejection> {
+        let relay = RelayedFork::new(&fork_url, fork_cache && fork_block_number.is_some())
+            .map_err(warp::reject::custom)?;
+        let fork = Self::fork(
+            env,
+            relay,
+            fork_block_number,
+            gas_limit,
+            tracing,
+            fork_cache,
+            etherscan_key,
+        );
+
+        let start = Instant::now();
+        let span = tracing::info_span!(target: "ts::api", "fork", ?fork_block_number);
+        let result = tokio::time::timeout(fork_timeout, fork.instrument(span)).await;
+
+        let chain_id = match &result {
+            Ok(Ok(evm)) => Some(evm.get_chain_id().as_u64()),
+            _ => None,
+        };
+        metrics::record_fork(chain_id, chain_id.is_some(), start.elapsed());
+
+        match result {
+            Ok(evm) => evm.map_err(warp::reject::custom),
+            Err(_) => Err(warp::reject::custom(ForkTimeoutError)),
+        }
+    }
+
+    async fn fork(
+        env: Option<Env>,
+        relay: RelayedFork,
+        fork_block_number: Option<u64>,
+        gas_limit: u64,
+        tracing: bool,
+        fork_cache: bool,
         etherscan_key: Option<String>,
-    ) -> Self {
+    ) -> Result<Self, ForkError> {
+        let fork_url = relay.url();
         let evm_opts = EvmOpts {
             fork_url: Some(fork_url.clone()),
             fork_block_number,
@@ -89,12 +179,21 @@ impl Evm {
 
         let fork_opts = CreateFork {
             url: fork_url,
-            enable_caching: true,
-            env: evm_opts.evm_env_blocking().unwrap(),
+            enable_caching: fork_cache,
+            env: evm_opts.evm_env().await.map_err(ForkError)?,
             evm_opts,
         };
 
-        let db = Backend::spawn(Some(fork_opts.clone()));
+        let chain: Chain = fork_opts.env.cfg.chain_id.to::<u64>().into();
+
+        // Spawning the backend fetches the initial fork state synchronously, so it has to run
+        // on the blocking pool to avoid stalling the worker threads serving other requests.
+        let db = tokio::task::spawn_blocking({
+            let fork_opts = fork_opts.clone();
+            move || Backend::spawn(Some(fork_opts))
+        })
+        .await
+        .map_err(|err| ForkError(err.into()))?;
 
         let mut builder = ExecutorBuilder::default()
             .with_gas_limit(gas_limit.into())
@@ -106,14 +205,21 @@ impl Evm {
             builder = builder.with_config(fork_opts.env.clone());
         }
 
-        let executor = builder.build(db);
+        let mut executor = builder.build(db);
+        // Mocks are answered by the cheatcodes inspector, which would also run any cheatcode
+        // called, reading the environment of the server for instance. On forks only accounts
+        // granted access may call cheatcodes, so none is.
+        for account in [CALLER, TEST_CONTRACT_ADDRESS, DEFAULT_CREATE2_DEPLOYER] {
+            executor.backend_mut().revoke_cheatcode_access(account);
+        }
+        let hardfork = Hardfork::default();
+        executor.env_mut().cfg.spec_id = hardfork.into();
 
         let foundry_config = foundry_config::Config {
             etherscan_api_key: etherscan_key,
             ..Default::default()
         };
 
-        let chain: Chain = fork_opts.env.cfg.chain_id.to::<u64>().into();
         let etherscan_identifier = EtherscanIdentifier::new(&foundry_config, Some(chain)).ok();
         let mut decoder = CallTraceDecoderBuilder::new().with_verbosity(5).build();
 
@@ -123,41 +229,42 @@ impl Evm {
             decoder.add_signature_identifier(identifier);
         }
 
-        Evm {
+        Ok(Evm {
             executor,
             decoder,
             etherscan_identifier,
-        }
+            hardfork,
+            schedule: Schedule::default(),
+            price_feeds: Vec::new(),
+            _relay: relay,
+        })
     }
 
     pub async fn call_raw(&mut self, call: CallRawRequest) -> Result<CallRawResult, EvmError> {
         self.set_access_list(call.access_list);
-        let res = self
-            .executor
-            .call_raw(
-                call.from,
-                call.to,
-                call.data.unwrap_or_default().0,
-                call.value.unwrap_or_default(),
-            )
+        let res = tracing::info_span!(target: "ts::api", "execute")
+            .in_scope(|| {
+                self.executor.call_raw(
+                    call.from,
+                    call.to,
+                    call.data.unwrap_or_default().0,
+                    call.value.unwrap_or_default(),
+                )
+            })
             .map_err(|err| {
-                dbg!(&err);
+                tracing::warn!(target: "ts::api", ?err, "execution failed");
                 EvmError(err)
             })?;
 
         let formatted_trace = if call.format_trace {
-            let mut output = String::new();
-            for trace in &mut res.traces.clone() {
-                if let Some(identifier) = &mut self.etherscan_identifier {
-                    self.decoder.identify(trace, identifier);
-                }
-                self.decoder.decode(trace).await;
-                output.push_str(format!("{trace}").as_str());
-            }
-            Some(output)
+            Some(self.format_trace(&res.traces).await)
         } else {
             None
         };
+        let state_diff = res
+            .state_changeset
+            .as_ref()
+            .map(|changeset| self.state_diff(changeset));
 
         Ok(CallRawResult {
             gas_used: res.gas_used,
@@ -168,9 +275,90 @@ impl Evm {
             exit_reason: res.exit_reason,
             return_data: Bytes(res.result),
             formatted_trace,
+            hardfork: self.hardfork,
+            state_diff,
         })
     }
 
+    /// The differences between `changeset` and the current state, which must not have been
+    /// committed yet.
+    fn state_diff(&self, changeset: &State) -> StateDiff {
+        changeset
+            .iter()
+            .filter_map(|(address, account)| {
+                let original = self
+                    .executor
+                    .backend()
+                    .basic(*address)
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default();
+
+                let diff = AccountDiff {
+                    balance: (original.balance != account.info.balance).then(|| Change {
+                        from: ru256_to_u256(original.balance),
+                        to: ru256_to_u256(account.info.balance),
+                    }),
+                    nonce: (original.nonce != account.info.nonce).then_some(Change {
+                        from: original.nonce,
+                        to: account.info.nonce,
+                    }),
+                    storage: account
+                        .storage
+                        .iter()
+                        .filter(|(_, slot)| slot.original_value != slot.present_value)
+                        .map(|(key, slot)| {
+                            (
+                                Hash::from(key.to_be_bytes::<32>()),
+                                Change {
+                                    from: Hash::from(slot.original_value.to_be_bytes::<32>()),
+                                    to: Hash::from(slot.present_value.to_be_bytes::<32>()),
+                                },
+                            )
+                        })
+                        .collect(),
+                };
+
+                (!diff.is_empty()).then(|| (b160_to_h160(*address), diff))
+            })
+            .collect()
+    }
+
+    /// Decodes `traces` into a human readable call tree, identifying contracts through Etherscan
+    /// if possible.
+    async fn format_trace(&mut self, traces: &Option<CallTraceArena>) -> String {
+        let span = tracing::info_span!(target: "ts::api", "decode_trace");
+        async move {
+            let mut output = String::new();
+            for trace in &mut traces.clone() {
+                if let Some(identifier) = &mut self.etherscan_identifier {
+                    self.decoder.identify(trace, identifier);
+                }
+                self.decoder.decode(trace).await;
+                output.push_str(format!("{trace}").as_str());
+            }
+            output
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Runs `apply`, putting the state and mocks back as they were if it fails, so that nothing
+    /// it changed is left behind when it fails halfway.
+    pub fn atomically<T, E>(
+        &mut self,
+        apply: impl FnOnce(&mut Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let checkpoint = self.executor.clone();
+        let price_feeds = self.price_feeds.len();
+        let result = apply(self);
+        if result.is_err() {
+            self.executor = checkpoint;
+            self.price_feeds.truncate(price_feeds);
+        }
+        result
+    }
+
     pub fn override_account(
         &mut self,
         address: Address,
@@ -180,15 +368,15 @@ impl Evm {
         storage: Option<StorageOverride>,
     ) -> Result<(), OverrideError> {
         let address = h160_to_b160(address);
-        let mut account = Account {
-            info: self
-                .executor
+        // The account keeps what isn't overridden, including its code and storage, whether or
+        // not it existed before.
+        let mut account = Account::from(
+            self.executor
                 .backend()
                 .basic(address)
                 .map_err(|_| OverrideError)?
                 .unwrap_or_default(),
-            ..Account::new_not_existing()
-        };
+        );
 
         if let Some(balance) = balance {
             account.info.balance = u256_to_ru256(balance);
@@ -197,6 +385,12 @@ impl Evm {
             account.info.nonce = nonce;
         }
         if let Some(code) = code {
+            // The hash of the previous code would otherwise still be used to look it up.
+            account.info.code_hash = if code.is_empty() {
+                KECCAK_EMPTY
+            } else {
+                B256::from(keccak256(&code))
+            };
             account.info.code = Some(Bytecode::new_raw(code.to_vec().into()));
         }
         if let Some(storage) = storage {
@@ -228,32 +422,96 @@ impl Evm {
     ) -> Result<CallRawResult, EvmError> {
         self.executor.set_gas_limit(gas_limit.into());
         self.set_access_list(call.access_list);
-        let res = self
-            .executor
-            .call_raw_committing(
-                call.from,
-                call.to,
-                call.data.unwrap_or_default().0,
-                call.value.unwrap_or_default(),
-            )
+        let res = tracing::info_span!(target: "ts::api", "execute")
+            .in_scope(|| {
+                self.executor.call_raw_committing(
+                    call.from,
+                    call.to,
+                    call.data.unwrap_or_default().0,
+                    call.value.unwrap_or_default(),
+                )
+            })
             .map_err(|err| {
-                dbg!(&err);
+                tracing::warn!(target: "ts::api", ?err, "execution failed");
                 EvmError(err)
             })?;
 
         let formatted_trace = if call.format_trace {
-            let mut output = String::new();
-            for trace in &mut res.traces.clone() {
-                if let Some(identifier) = &mut self.etherscan_identifier {
-                    self.decoder.identify(trace, identifier);
+            Some(self.format_trace(&res.traces).await)
+        } else {
+            None
+        };
+
+        Ok(CallRawResult {
+            gas_used: res.gas_used,
+            block_number: res.env.block.number.to(),
+            success: !res.reverted,
+            trace: res.traces,
+            logs: res.logs,
+            exit_reason: res.exit_reason,
+            return_data: Bytes(res.result),
+            formatted_trace,
+            hardfork: self.hardfork,
+            state_diff: None,
+        })
+    }
+
+    /// Runs a mined `transaction` as it was sent, paying for its gas at its own price on top of
+    /// the base fee of the block, and with its nonce and chain ID checked. Its changes are
+    /// committed if `commit` is set, whether it succeeds or not.
+    pub async fn transact(
+        &mut self,
+        transaction: &Transaction,
+        commit: bool,
+        format_trace: bool,
+    ) -> Result<CallRawResult, EvmError> {
+        self.set_access_list(transaction.access_list.clone());
+        let mut env = self.executor.env().clone();
+        env.tx.caller = h160_to_b160(transaction.from);
+        env.tx.transact_to = match transaction.to {
+            Some(to) => TransactTo::Call(h160_to_b160(to)),
+            None => TransactTo::Create(CreateScheme::Create),
+        };
+        env.tx.data = transaction.input.0.clone();
+        env.tx.value = u256_to_ru256(transaction.value);
+        env.tx.gas_limit = transaction.gas.as_u64();
+        // Transactions with a fee market have their gas price set to what they paid, but run
+        // with their maximum fee like any other.
+        env.tx.gas_price = u256_to_ru256(
+            transaction
+                .max_fee_per_gas
+                .or(transaction.gas_price)
+                .unwrap_or_default(),
+        );
+        env.tx.gas_priority_fee = transaction.max_priority_fee_per_gas.map(u256_to_ru256);
+        env.tx.nonce = Some(transaction.nonce.as_u64());
+        env.tx.chain_id = transaction.chain_id.map(|chain_id| chain_id.as_u64());
+
+        let res = tracing::info_span!(target: "ts::api", "execute")
+            .in_scope(|| {
+                if commit {
+                    self.executor.commit_tx_with_env(env)
+                } else {
+                    self.executor.call_raw_with_env(env)
                 }
-                self.decoder.decode(trace).await;
-                output.push_str(format!("{trace}").as_str());
-            }
-            Some(output)
+            })
+            .map_err(|err| {
+                tracing::warn!(target: "ts::api", ?err, "execution failed");
+                EvmError(err)
+            })?;
+
+        let formatted_trace = if format_trace {
+            Some(self.format_trace(&res.traces).await)
         } else {
             None
         };
+        let state_diff = if commit {
+            None
+        } else {
+            res.state_changeset
+                .as_ref()
+                .map(|changeset| self.state_diff(changeset))
+        };
 
         Ok(CallRawResult {
             gas_used: res.gas_used,
@@ -264,9 +522,118 @@ impl Evm {
             exit_reason: res.exit_reason,
             return_data: Bytes(res.result),
             formatted_trace,
+            hardfork: self.hardfork,
+            state_diff,
         })
     }
 
+    /// Makes calls to `to` with data starting with `data` return `returned`, or revert with it,
+    /// without running its code. Calls to precompiles are answered the same way.
+    pub fn mock_call(&mut self, to: Address, data: Bytes, returned: Bytes, revert: bool) {
+        let ret_type = if revert {
+            InstructionResult::Revert
+        } else {
+            InstructionResult::Return
+        };
+        self.executor
+            .inspector_config_mut()
+            .cheatcodes
+            .get_or_insert_with(Cheatcodes::default)
+            .mocked_calls
+            .entry(to)
+            .or_default()
+            .insert(
+                MockCallDataContext {
+                    calldata: data,
+                    value: None,
+                },
+                MockCallReturnData {
+                    ret_type,
+                    data: returned,
+                },
+            );
+    }
+
+    /// Mocks the price feed `feed` for the rest of the simulation, its latest round being updated
+    /// at the block timestamp even once that changes.
+    pub fn mock_price_feed(&mut self, feed: Mock) {
+        self.price_feeds.push(feed);
+        self.mock_price_feeds();
+    }
+
+    /// Mocks the price feeds again, so that they answer with the current block timestamp.
+    fn mock_price_feeds(&mut self) {
+        let timestamp = self.get_block_timestamp();
+        for feed in self.price_feeds.clone() {
+            for (address, data, returned, revert) in feed.calls(timestamp) {
+                self.mock_call(address, data, returned, revert);
+            }
+        }
+    }
+
+    pub fn set_gas_limit(&mut self, gas_limit: u64) {
+        self.executor.set_gas_limit(gas_limit.into());
+    }
+
+    /// Sets the block environment to that of `block`, to run its transactions as they were.
+    pub fn set_block_header<TX>(&mut self, block: &Block<TX>) {
+        let env = &mut self.executor.env_mut().block;
+        if let Some(number) = block.number {
+            env.number = u256_to_ru256(number.as_u64().into());
+        }
+        if let Some(author) = block.author {
+            env.coinbase = h160_to_b160(author);
+        }
+        env.timestamp = u256_to_ru256(block.timestamp);
+        env.difficulty = u256_to_ru256(block.difficulty);
+        env.prevrandao = block.mix_hash.map(h256_to_b256);
+        if let Some(base_fee) = block.base_fee_per_gas {
+            env.basefee = u256_to_ru256(base_fee);
+        }
+        env.gas_limit = u256_to_ru256(block.gas_limit);
+        self.mock_price_feeds();
+    }
+
+    /// Runs the following calls with the rules of `hardfork`.
+    pub fn set_hardfork(&mut self, hardfork: Hardfork) {
+        self.executor.env_mut().cfg.spec_id = hardfork.into();
+        self.hardfork = hardfork;
+    }
+
+    /// Chooses the hard forks of the following calls with `schedule`, and runs them with that of
+    /// the current block, or `requested`, until another is selected.
+    pub fn set_schedule(
+        &mut self,
+        schedule: Schedule,
+        requested: Option<Hardfork>,
+    ) -> Result<Hardfork, Rejection> {
+        self.schedule = schedule;
+        self.select_hardfork(requested)
+    }
+
+    /// Runs the following calls with `requested`, or the hard fork of the current block, which
+    /// may have changed since the last one was selected.
+    pub fn select_hardfork(&mut self, requested: Option<Hardfork>) -> Result<Hardfork, Rejection> {
+        let hardfork = self.schedule.select(
+            self.get_block().as_u64(),
+            self.get_block_timestamp().as_u64(),
+            requested,
+        )?;
+        self.set_hardfork(hardfork);
+        Ok(hardfork)
+    }
+
+    pub fn get_hardfork(&self) -> Hardfork {
+        self.hardfork
+    }
+
+    /// Whether `address` is a precompile under the current hard fork, which runs whatever the
+    /// code at its address.
+    pub fn is_precompile(&self, address: Address) -> bool {
+        let spec = PrecompileSpecId::from_spec_id(self.executor.env().cfg.spec_id);
+        Precompiles::new(spec).contains(&h160_to_b160(address))
+    }
+
     pub async fn set_block(&mut self, number: u64) -> Result<(), EvmError> {
         self.executor.env_mut().block.number = Uint::from(number).into();
         Ok(())
@@ -278,6 +645,7 @@ impl Evm {
 
     pub async fn set_block_timestamp(&mut self, timestamp: u64) -> Result<(), EvmError> {
         self.executor.env_mut().block.timestamp = Uint::from(timestamp).into();
+        self.mock_price_feeds();
         Ok(())
     }
 
@@ -285,6 +653,72 @@ impl Evm {
         self.executor.env().block.timestamp.into()
     }
 
+    pub fn get_balance(&self, address: Address) -> Result<Uint, EvmError> {
+        let account = self
+            .executor
+            .backend()
+            .basic(h160_to_b160(address))
+            .map_err(|err| EvmError(err.into()))?;
+        Ok(account.map_or(Uint::zero(), |info| ru256_to_u256(info.balance)))
+    }
+
+    /// The balance, nonce and code of `address`.
+    pub fn get_account(&self, address: Address) -> Result<(Uint, u64, Bytes), EvmError> {
+        let backend = self.executor.backend();
+        let info = backend
+            .basic(h160_to_b160(address))
+            .map_err(|err| EvmError(err.into()))?
+            .unwrap_or_default();
+        let code = match info.code {
+            Some(code) => code,
+            None => backend
+                .code_by_hash(info.code_hash)
+                .map_err(|err| EvmError(err.into()))?,
+        };
+
+        Ok((
+            ru256_to_u256(info.balance),
+            info.nonce,
+            code.original_bytes().into(),
+        ))
+    }
+
+    pub fn get_storage(&self, address: Address, slot: Hash) -> Result<Uint, EvmError> {
+        let value = self
+            .executor
+            .backend()
+            .storage(
+                h160_to_b160(address),
+                u256_to_ru256(Uint::from_big_endian(slot.as_bytes())),
+            )
+            .map_err(|err| EvmError(err.into()))?;
+        Ok(ru256_to_u256(value))
+    }
+
+    /// Calls `to` without committing, returning the data it returned and the storage slots of
+    /// `to` it accessed.
+    pub fn probe(&mut self, to: Address, data: Bytes) -> Result<(Bytes, Vec<Hash>), EvmError> {
+        let res = self
+            .executor
+            .call_raw(Address::zero(), to, data.0, Uint::zero())
+            .map_err(EvmError)?;
+
+        let slots = res
+            .state_changeset
+            .as_ref()
+            .and_then(|changeset| changeset.get(&h160_to_b160(to)))
+            .map(|account| {
+                account
+                    .storage
+                    .keys()
+                    .map(|key| Hash::from(key.to_be_bytes::<32>()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok((Bytes(res.result), slots))
+    }
+
     pub fn get_chain_id(&self) -> Uint {
         self.executor.env().cfg.chain_id.into()
     }
@@ -0,0 +1,389 @@
+use revm::primitives::SpecId;
+use serde::{Deserialize, Serialize};
+use warp::Rejection;
+
+use crate::config::Config;
+use crate::errors::UnsupportedHardforkError;
+
+/// The rules transactions are run with, named after the hard fork which introduced them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Hardfork {
+    Frontier,
+    Homestead,
+    Tangerine,
+    SpuriousDragon,
+    Byzantium,
+    Constantinople,
+    Petersburg,
+    Istanbul,
+    Berlin,
+    London,
+    Merge,
+    /// The latest hard fork the EVM supports, used for chains whose hard forks aren't known.
+    #[default]
+    Shanghai,
+    /// Not supported by the EVM yet, rejected when asked for or for blocks belonging to it.
+    Cancun,
+}
+
+/// When a hard fork activates: at a block, or for those scheduled by time, like the ones after
+/// the merge, at the first block with a timestamp at least this one.
+#[derive(Debug, Clone, Copy)]
+enum Activation {
+    Block(u64),
+    Timestamp(u64),
+}
+
+use Activation::{Block, Timestamp};
+
+/// The hard forks of each chain with a fork URL by default, in order, by chain id. Chains with a
+/// single hard fork always run with it, as theirs don't follow Ethereum's.
+const SCHEDULES: &[(u64, &[(Activation, Hardfork)])] = &[
+    // ethereum
+    (
+        1,
+        &[
+            (Block(0), Hardfork::Frontier),
+            (Block(1_150_000), Hardfork::Homestead),
+            (Block(2_463_000), Hardfork::Tangerine),
+            (Block(2_675_000), Hardfork::SpuriousDragon),
+            (Block(4_370_000), Hardfork::Byzantium),
+            // Constantinople was replaced by Petersburg before activating on mainnet.
+            (Block(7_280_000), Hardfork::Petersburg),
+            (Block(9_069_000), Hardfork::Istanbul),
+            (Block(12_244_000), Hardfork::Berlin),
+            (Block(12_965_000), Hardfork::London),
+            (Block(15_537_394), Hardfork::Merge),
+            (Timestamp(1_681_338_455), Hardfork::Shanghai),
+            (Timestamp(1_710_338_135), Hardfork::Cancun),
+        ],
+    ),
+    (
+        5,
+        &[
+            (Block(0), Hardfork::Petersburg),
+            (Block(1_561_651), Hardfork::Istanbul),
+            (Block(4_460_644), Hardfork::Berlin),
+            (Block(5_062_605), Hardfork::London),
+            (Block(7_382_819), Hardfork::Merge),
+            (Timestamp(1_678_832_736), Hardfork::Shanghai),
+            (Timestamp(1_705_473_120), Hardfork::Cancun),
+        ],
+    ),
+    (
+        11155111,
+        &[
+            (Block(0), Hardfork::London),
+            (Block(1_735_371), Hardfork::Merge),
+            (Timestamp(1_677_557_088), Hardfork::Shanghai),
+            (Timestamp(1_706_655_072), Hardfork::Cancun),
+        ],
+    ),
+    // polygon
+    (
+        137,
+        &[
+            (Block(0), Hardfork::Petersburg),
+            (Block(3_395_000), Hardfork::Istanbul),
+            (Block(14_750_000), Hardfork::Berlin),
+            (Block(23_850_000), Hardfork::London),
+            (Block(50_523_000), Hardfork::Shanghai),
+            (Block(54_876_000), Hardfork::Cancun),
+        ],
+    ),
+    (
+        80001,
+        &[
+            (Block(0), Hardfork::Petersburg),
+            (Block(2_722_000), Hardfork::Istanbul),
+            (Block(13_996_000), Hardfork::Berlin),
+            (Block(22_640_000), Hardfork::London),
+            (Block(41_874_000), Hardfork::Shanghai),
+            (Block(45_648_608), Hardfork::Cancun),
+        ],
+    ),
+    // avalanche, whose Apricot phases 2 and 3, Durango and Etna bring the rules of Berlin,
+    // London, Shanghai and Cancun
+    (
+        43114,
+        &[
+            (Block(0), Hardfork::Istanbul),
+            (Timestamp(1_620_644_400), Hardfork::Berlin),
+            (Timestamp(1_629_813_600), Hardfork::London),
+            (Timestamp(1_709_740_800), Hardfork::Shanghai),
+            (Timestamp(1_734_368_400), Hardfork::Cancun),
+        ],
+    ),
+    (
+        43113,
+        &[
+            (Block(0), Hardfork::Istanbul),
+            (Timestamp(1_620_223_200), Hardfork::Berlin),
+            (Timestamp(1_629_140_400), Hardfork::London),
+            (Timestamp(1_707_840_000), Hardfork::Shanghai),
+            (Timestamp(1_732_550_400), Hardfork::Cancun),
+        ],
+    ),
+    // fantom, which never had Shanghai
+    (250, &[(Block(0), Hardfork::London)]),
+    (4002, &[(Block(0), Hardfork::London)]),
+    // xdai
+    (
+        100,
+        &[
+            (Block(0), Hardfork::Byzantium),
+            (Block(1_604_400), Hardfork::Petersburg),
+            (Block(7_298_030), Hardfork::Istanbul),
+            (Block(16_101_500), Hardfork::Berlin),
+            (Block(19_040_000), Hardfork::London),
+            (Block(25_349_536), Hardfork::Merge),
+            (Timestamp(1_690_889_660), Hardfork::Shanghai),
+            (Timestamp(1_710_181_820), Hardfork::Cancun),
+        ],
+    ),
+    // bsc, where Berlin and London came with Hertz
+    (
+        56,
+        &[
+            (Block(0), Hardfork::Istanbul),
+            (Block(31_302_048), Hardfork::London),
+            (Timestamp(1_705_996_800), Hardfork::Shanghai),
+            (Timestamp(1_718_863_500), Hardfork::Cancun),
+        ],
+    ),
+    (
+        97,
+        &[
+            (Block(0), Hardfork::Istanbul),
+            (Block(31_103_030), Hardfork::London),
+            (Timestamp(1_702_972_800), Hardfork::Shanghai),
+            (Timestamp(1_713_330_442), Hardfork::Cancun),
+        ],
+    ),
+    // arbitrum, whose rules change with ArbOS upgrades rather than at known blocks, so that its
+    // blocks are taken to follow the last ArbOS version of each network
+    (42161, &[(Block(0), Hardfork::Cancun)]),
+    (421613, &[(Block(0), Hardfork::Shanghai)]),
+    // optimism, Bedrock, Canyon and Ecotone bringing the rules of the merge, Shanghai and Cancun
+    (
+        10,
+        &[
+            (Block(0), Hardfork::Berlin),
+            (Block(105_235_063), Hardfork::Merge),
+            (Timestamp(1_704_992_401), Hardfork::Shanghai),
+            (Timestamp(1_710_374_401), Hardfork::Cancun),
+        ],
+    ),
+    (
+        420,
+        &[
+            (Block(0), Hardfork::Berlin),
+            (Block(4_061_224), Hardfork::Merge),
+            (Timestamp(1_699_981_200), Hardfork::Shanghai),
+            (Timestamp(1_707_238_800), Hardfork::Cancun),
+        ],
+    ),
+];
+
+impl Hardfork {
+    /// The hard fork of the block `block_number` of `chain_id` with `timestamp` belongs to,
+    /// `None` if the hard forks of the chain aren't known.
+    pub fn at(chain_id: u64, block_number: u64, timestamp: u64) -> Option<Hardfork> {
+        let (_, forks) = SCHEDULES.iter().find(|(id, _)| *id == chain_id)?;
+        forks
+            .iter()
+            .rev()
+            .find(|(activation, _)| match activation {
+                Block(block) => *block <= block_number,
+                Timestamp(time) => *time <= timestamp,
+            })
+            .map(|(_, hardfork)| *hardfork)
+    }
+
+    pub fn is_supported(self) -> bool {
+        self != Hardfork::Cancun
+    }
+}
+
+/// How the hard fork each transaction of a fork runs with is chosen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Schedule {
+    chain_id: u64,
+    /// Replaces the hard fork of each block, requested when forking or configured for the chain.
+    fixed: Option<Hardfork>,
+}
+
+impl Schedule {
+    /// The hard forks of the blocks of `chain_id`, unless `requested` or one configured for the
+    /// chain replaces them.
+    pub fn new(config: &Config, chain_id: u64, requested: Option<Hardfork>) -> Self {
+        Schedule {
+            chain_id,
+            fixed: requested.or_else(|| config.hardfork_for(chain_id)),
+        }
+    }
+
+    /// The hard fork to run a transaction of `block_number` at `timestamp` with: `requested` if
+    /// set, otherwise the fixed one if any, otherwise the one the block belongs to.
+    pub fn select(
+        &self,
+        block_number: u64,
+        timestamp: u64,
+        requested: Option<Hardfork>,
+    ) -> Result<Hardfork, Rejection> {
+        let hardfork = requested
+            .or(self.fixed)
+            .or_else(|| Hardfork::at(self.chain_id, block_number, timestamp))
+            .unwrap_or_else(|| {
+                tracing::warn!(
+                    target: "ts::api",
+                    chain_id = self.chain_id,
+                    "hard forks of the chain aren't known, running with the latest supported one"
+                );
+                Hardfork::default()
+            });
+
+        // Blocks of hard forks the EVM doesn't support are rejected rather than run with the
+        // rules of another one.
+        if !hardfork.is_supported() {
+            return Err(warp::reject::custom(UnsupportedHardforkError));
+        }
+
+        Ok(hardfork)
+    }
+}
+
+impl From<Hardfork> for SpecId {
+    fn from(hardfork: Hardfork) -> Self {
+        match hardfork {
+            Hardfork::Frontier => SpecId::FRONTIER,
+            Hardfork::Homestead => SpecId::HOMESTEAD,
+            Hardfork::Tangerine => SpecId::TANGERINE,
+            Hardfork::SpuriousDragon => SpecId::SPURIOUS_DRAGON,
+            Hardfork::Byzantium => SpecId::BYZANTIUM,
+            Hardfork::Constantinople => SpecId::CONSTANTINOPLE,
+            Hardfork::Petersburg => SpecId::PETERSBURG,
+            Hardfork::Istanbul => SpecId::ISTANBUL,
+            Hardfork::Berlin => SpecId::BERLIN,
+            Hardfork::London => SpecId::LONDON,
+            Hardfork::Merge => SpecId::MERGE,
+            Hardfork::Shanghai => SpecId::SHANGHAI,
+            Hardfork::Cancun => SpecId::CANCUN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hardfork, Schedule};
+    use crate::config::{config, ChainConfig, Config};
+
+    #[test]
+    fn test_hardfork_at() {
+        assert_eq!(Hardfork::at(1, 0, 1_438_269_973), Some(Hardfork::Frontier));
+        assert_eq!(
+            Hardfork::at(1, 12_964_999, 1_628_166_812),
+            Some(Hardfork::Berlin)
+        );
+        assert_eq!(
+            Hardfork::at(1, 12_965_000, 1_628_166_822),
+            Some(Hardfork::London)
+        );
+        // Shanghai and Cancun activated at a timestamp rather than a block.
+        assert_eq!(
+            Hardfork::at(1, 17_034_869, 1_681_338_443),
+            Some(Hardfork::Merge)
+        );
+        assert_eq!(
+            Hardfork::at(1, 17_034_870, 1_681_338_455),
+            Some(Hardfork::Shanghai)
+        );
+        assert_eq!(
+            Hardfork::at(1, 19_426_586, 1_710_338_123),
+            Some(Hardfork::Shanghai)
+        );
+        assert_eq!(
+            Hardfork::at(1, 19_426_587, 1_710_338_135),
+            Some(Hardfork::Cancun)
+        );
+        assert_eq!(
+            Hardfork::at(11155111, 1_000, 1_656_000_000),
+            Some(Hardfork::London)
+        );
+        assert_eq!(
+            Hardfork::at(137, 50_522_999, 1_701_000_000),
+            Some(Hardfork::London)
+        );
+        assert_eq!(
+            Hardfork::at(10, 110_000_000, 1_690_000_000),
+            Some(Hardfork::Merge)
+        );
+        assert_eq!(
+            Hardfork::at(250, 60_000_000, 1_700_000_000),
+            Some(Hardfork::London)
+        );
+        assert_eq!(Hardfork::at(31337, 1, 1_700_000_000), None);
+    }
+
+    #[test]
+    fn test_schedule_select() {
+        let config = Config {
+            chains: vec![ChainConfig {
+                chain_id: 8453,
+                fork_url: "https://mainnet.base.org".to_string(),
+                hardfork: Some(Hardfork::London),
+            }],
+            ..config()
+        };
+
+        // Cancun blocks aren't run with the rules of another hard fork unless asked to.
+        let mainnet = Schedule::new(&config, 1, None);
+        assert_eq!(
+            mainnet.select(17_034_870, 1_681_338_455, None).unwrap(),
+            Hardfork::Shanghai
+        );
+        assert!(mainnet.select(19_426_587, 1_710_338_135, None).is_err());
+        assert_eq!(
+            mainnet
+                .select(19_426_587, 1_710_338_135, Some(Hardfork::Shanghai))
+                .unwrap(),
+            Hardfork::Shanghai
+        );
+
+        // The hard fork requested when forking replaces those of the blocks, but not those
+        // requested for a transaction.
+        let pinned = Schedule::new(&config, 1, Some(Hardfork::Merge));
+        assert_eq!(
+            pinned.select(19_426_587, 1_710_338_135, None).unwrap(),
+            Hardfork::Merge
+        );
+        assert_eq!(
+            pinned
+                .select(19_426_587, 1_710_338_135, Some(Hardfork::London))
+                .unwrap(),
+            Hardfork::London
+        );
+
+        let configured = Schedule::new(&config, 8453, None);
+        assert_eq!(
+            configured.select(1, 1_700_000_000, None).unwrap(),
+            Hardfork::London
+        );
+
+        let unknown = Schedule::new(&config, 31337, None);
+        assert_eq!(
+            unknown.select(1, 1_700_000_000, None).unwrap(),
+            Hardfork::Shanghai
+        );
+    }
+
+    #[test]
+    fn test_hardfork_deserialize() {
+        let hardfork: Hardfork =
+            serde_json::from_value(serde_json::json!("spuriousDragon")).unwrap();
+        assert_eq!(hardfork, Hardfork::SpuriousDragon);
+        assert!(!Hardfork::Cancun.is_supported());
+        assert!(serde_json::from_value::<Hardfork>(serde_json::json!("prague")).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use ethers::providers::{Http, Middleware, Provider, ProviderError, RpcError};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use warp::{hyper::StatusCode, reply::Json, reply::WithStatus, Rejection};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HealthResponse {
+    pub status: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub chains: Vec<ChainStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainStatus {
+    /// The chain the fork URL is expected to serve, `None` for `FORK_URL` which serves any chain.
+    pub expected_chain_id: Option<u64>,
+    /// The chain id reported by the fork URL, if it responded.
+    pub chain_id: Option<u64>,
+    pub ready: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+pub async fn health() -> Result<Json, Rejection> {
+    Ok(warp::reply::json(&HealthResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+pub async fn ready(config: Config) -> Result<WithStatus<Json>, Rejection> {
+    let timeout = Duration::from_secs(config.fork_timeout);
+    let chains = join_all(
+        fork_urls(&config)
+            .into_iter()
+            .map(|(expected_chain_id, url)| check_chain(expected_chain_id, url, timeout)),
+    )
+    .await;
+
+    let ready = chains.iter().all(|chain| chain.ready);
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ReadinessResponse { ready, chains }),
+        code,
+    ))
+}
+
+fn fork_urls(config: &Config) -> Vec<(Option<u64>, String)> {
+    match &config.fork_url {
+        Some(fork_url) => vec![(None, fork_url.clone())],
+        None => config
+            .chains
+            .iter()
+            .filter(|chain| config.ready_chains.contains(&chain.chain_id))
+            .map(|chain| (Some(chain.chain_id), chain.fork_url.clone()))
+            .collect(),
+    }
+}
+
+async fn check_chain(
+    expected_chain_id: Option<u64>,
+    url: String,
+    timeout: Duration,
+) -> ChainStatus {
+    let start = Instant::now();
+    // Errors are reported as codes only, as the underlying messages may contain the URL and
+    // with it any credentials embedded in it.
+    let result = match Provider::<Http>::try_from(url.as_str()) {
+        Ok(provider) => match tokio::time::timeout(timeout, provider.get_chainid()).await {
+            Ok(Ok(chain_id)) => Ok(chain_id.as_u64()),
+            Ok(Err(err)) => {
+                tracing::warn!(
+                    target: "ts::api",
+                    "Readiness check failed for chain {expected_chain_id:?}: {}",
+                    error_kind(&err)
+                );
+                Err("UPSTREAM_ERROR")
+            }
+            Err(_) => Err("UPSTREAM_TIMEOUT"),
+        },
+        Err(_) => Err("INVALID_FORK_URL"),
+    };
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    match result {
+        Ok(chain_id) if expected_chain_id.map_or(true, |expected| expected == chain_id) => {
+            ChainStatus {
+                expected_chain_id,
+                chain_id: Some(chain_id),
+                ready: true,
+                latency_ms,
+                error: None,
+            }
+        }
+        Ok(chain_id) => ChainStatus {
+            expected_chain_id,
+            chain_id: Some(chain_id),
+            ready: false,
+            latency_ms,
+            error: Some("INCORRECT_CHAIN_ID".to_string()),
+        },
+        Err(error) => ChainStatus {
+            expected_chain_id,
+            chain_id: None,
+            ready: false,
+            latency_ms,
+            error: Some(error.to_string()),
+        },
+    }
+}
+
+/// What went wrong in `err`, without its message, which may contain the URL of the node.
+fn error_kind(err: &ProviderError) -> String {
+    match err {
+        ProviderError::JsonRpcClientError(err) => match err.as_error_response() {
+            Some(response) => format!("JSON-RPC error {}", response.code),
+            None if err.as_serde_error().is_some() => "invalid response".to_string(),
+            None => "transport error".to_string(),
+        },
+        ProviderError::HTTPError(err) => match err.status() {
+            Some(status) => format!("HTTP status {}", status.as_u16()),
+            None => "HTTP error".to_string(),
+        },
+        ProviderError::SerdeJson(_) => "invalid response".to_string(),
+        _ => "provider error".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::providers::ProviderError;
+
+    use super::error_kind;
+
+    #[test]
+    fn test_error_kind() {
+        let err = ProviderError::CustomError(
+            "error sending request for url (https://mainnet.example/v3/secret)".to_string(),
+        );
+        assert_eq!(error_kind(&err), "provider error");
+
+        let err = serde_json::from_str::<u64>("\"https://mainnet.example/v3/secret\"").unwrap_err();
+        assert_eq!(
+            error_kind(&ProviderError::SerdeJson(err)),
+            "invalid response"
+        );
+    }
+}
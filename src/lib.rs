@@ -1,55 +1,169 @@
 use dashmap::DashMap;
+use ethers::abi::Address;
 use evm::Evm;
 use serde::de::DeserializeOwned;
-use simulation::{SimulationRequest, StatefulSimulationRequest};
+use simulation::{RerunRequest, SimulationRequest, StateOverride, StatefulSimulationRequest};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 
+pub mod auth;
+use auth::{with_client, Auth, Client, Route};
+
+pub mod call_bundle;
+use call_bundle::JsonRpcRequest;
+
 pub mod config;
 use config::Config;
 
 pub mod errors;
 pub mod evm;
+pub mod hardfork;
+pub mod health;
+
+pub mod limits;
+use limits::{with_permit, Limits};
+
+pub mod metrics;
+pub mod mocks;
+pub mod permits;
+pub mod relay;
+pub mod replay;
+use replay::{InsertRequest, ReplayBlockRequest, ReplayRequest};
+
+pub mod request_id;
+
+pub mod safe;
+use request_id::REQUEST_ID_HEADER;
+use safe::SafeSimulationRequest;
+
+pub mod sessions;
+use sessions::SessionJournal;
 
 pub mod simulation;
+pub mod storage;
+
+pub mod store;
+use store::SimulationStore;
+
+pub mod tokens;
+use tokens::TokenLayouts;
+
+/// How many token layouts are kept, so that tokens overridden again aren't probed again.
+const MAX_TOKEN_LAYOUTS: usize = 10_000;
+
+pub mod user_operation;
+use user_operation::UserOperationRequest;
 
 pub struct SharedSimulationState {
     pub evms: Arc<DashMap<Uuid, Arc<Mutex<Evm>>>>,
+    pub journals: DashMap<Uuid, SessionJournal>,
+    pub auth: Arc<Auth>,
+    pub limits: Arc<Limits>,
+    pub simulations: SimulationStore,
+    pub token_layouts: TokenLayouts,
+}
+
+impl SharedSimulationState {
+    pub fn new(config: &Config) -> Self {
+        SharedSimulationState {
+            evms: Arc::new(DashMap::new()),
+            journals: DashMap::new(),
+            auth: Arc::new(Auth::new(config)),
+            limits: Arc::new(Limits::new(config)),
+            simulations: SimulationStore::new(config.stored_simulations),
+            token_layouts: TokenLayouts::new(MAX_TOKEN_LAYOUTS),
+        }
+    }
 }
 
 pub fn simulate_routes(
     config: Config,
     state: Arc<SharedSimulationState>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    simulate(config.clone())
-        .or(simulate_bundle(config.clone()))
+    simulate(config.clone(), state.clone())
+        .or(simulate_bundle(config.clone(), state.clone()))
+        .or(simulate_safe(config.clone(), state.clone()))
+        .or(simulate_user_operation(config.clone(), state.clone()))
         .or(simulate_stateful_new(config.clone(), state.clone()))
         .or(simulate_stateful_end(state.clone()))
-        .or(simulate_stateful(config, state))
+        .or(simulate_stateful(config.clone(), state.clone()))
+        .or(simulate_stateful_state(config.clone(), state.clone()))
+        .or(get_simulation(state.clone()))
+        .or(rerun_simulation(config.clone(), state.clone()))
+        .or(replay(config.clone(), state.clone()))
+        .or(replay_block(config.clone(), state.clone()))
+        .or(insert(config.clone(), state.clone()))
+        .or(call_bundle(config, state))
+}
+
+/// Authenticates the client of a route running simulations, holding a simulation slot for it
+/// until the simulation is done to limit how many run at once.
+fn with_limited_client(
+    state: &SharedSimulationState,
+    route: Route,
+) -> impl Filter<Extract = (Client,), Error = Rejection> + Clone {
+    with_permit(state.limits.clone(), state.auth.clone(), route)
 }
 
 /// POST /simulate
-pub fn simulate(config: Config) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn simulate(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate")
         .and(warp::post())
+        .and(with_limited_client(&state, Route::Simulate))
         .and(json_body::<SimulationRequest>(&config))
         .and(with_config(config))
+        .and(with_state(state))
         .and_then(simulation::simulate)
 }
 
 /// POST /simulate-bundle
 pub fn simulate_bundle(
     config: Config,
+    state: Arc<SharedSimulationState>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate-bundle")
         .and(warp::post())
+        .and(with_limited_client(&state, Route::SimulateBundle))
         .and(json_body(&config))
         .and(with_config(config))
+        .and(with_state(state))
         .and_then(simulation::simulate_bundle)
 }
 
+/// POST /simulate-safe
+pub fn simulate_safe(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-safe")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::Simulate))
+        .and(json_body::<SafeSimulationRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(safe::simulate_safe)
+}
+
+/// POST /simulate-user-operation
+pub fn simulate_user_operation(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-user-operation")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::Simulate))
+        .and(json_body::<UserOperationRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(user_operation::simulate_user_operation)
+}
+
 /// POST /simulate-stateful
 pub fn simulate_stateful_new(
     config: Config,
@@ -57,6 +171,7 @@ pub fn simulate_stateful_new(
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate-stateful")
         .and(warp::post())
+        .and(with_limited_client(&state, Route::SimulateStateful))
         .and(json_body::<StatefulSimulationRequest>(&config))
         .and(with_config(config))
         .and(with_state(state))
@@ -69,6 +184,7 @@ pub fn simulate_stateful_end(
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate-stateful" / Uuid)
         .and(warp::delete())
+        .and(with_client(state.auth.clone(), Route::SimulateStateful))
         .and(with_state(state))
         .and_then(simulation::simulate_stateful_end)
 }
@@ -80,11 +196,147 @@ pub fn simulate_stateful(
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("simulate-stateful" / Uuid)
         .and(warp::post())
+        .and(with_limited_client(&state, Route::SimulateStateful))
         .and(json_body(&config))
         .and(with_state(state))
         .and_then(simulation::simulate_stateful)
 }
 
+/// POST /simulate-stateful/{statefulSimulationId}/state
+pub fn simulate_stateful_state(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-stateful" / Uuid / "state")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::SimulateStateful))
+        .and(json_body::<HashMap<Address, StateOverride>>(&config))
+        .and(with_state(state))
+        .and_then(simulation::simulate_stateful_state)
+}
+
+/// GET /simulations/{simulationId}
+pub fn get_simulation(
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulations" / Uuid)
+        .and(warp::get())
+        .and(with_client(state.auth.clone(), Route::Simulations))
+        .and(with_state(state))
+        .and_then(simulation::get_simulation)
+}
+
+/// POST /simulations/{simulationId}/rerun
+pub fn rerun_simulation(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulations" / Uuid / "rerun")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::Simulate))
+        .and(json_body::<RerunRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(simulation::rerun_simulation)
+}
+
+/// POST /replay
+pub fn replay(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("replay")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::Replay))
+        .and(json_body::<ReplayRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(replay::replay)
+}
+
+/// POST /replay-block
+pub fn replay_block(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("replay-block")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::Replay))
+        .and(json_body::<ReplayBlockRequest>(&config))
+        .and(with_config(config))
+        .and_then(replay::replay_block)
+}
+
+/// POST /simulate-in-block
+pub fn insert(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("simulate-in-block")
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::Replay))
+        .and(json_body::<InsertRequest>(&config))
+        .and(with_config(config))
+        .and(with_state(state))
+        .and_then(replay::insert)
+}
+
+/// POST /rpc/{chainId}
+pub fn call_bundle(
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("rpc" / u64)
+        .and(warp::post())
+        .and(with_limited_client(&state, Route::SimulateBundle))
+        .and(json_body::<JsonRpcRequest>(&config))
+        .and(with_config(config))
+        .and_then(call_bundle::rpc_handler)
+}
+
+/// GET /health
+pub fn health() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("health")
+        .and(warp::get())
+        .and_then(health::health)
+}
+
+/// GET /ready
+pub fn ready(config: Config) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("ready")
+        .and(warp::get())
+        .and(with_config(config))
+        .and_then(health::ready)
+}
+
+/// GET /metrics
+pub fn metrics() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and_then(metrics::metrics)
+}
+
+/// CORS for browser clients calling the API, `None` if no origin is allowed.
+pub fn cors(config: &Config) -> Option<warp::cors::Builder> {
+    if config.cors_origins.is_empty() {
+        return None;
+    }
+
+    // Browsers may pass their own request ids, and read the ones generated otherwise, as well as
+    // how long to wait after being rate limited.
+    let cors = warp::cors()
+        .allow_headers(config.cors_headers.iter().map(String::as_str))
+        .allow_header(REQUEST_ID_HEADER)
+        .expose_headers([REQUEST_ID_HEADER, "retry-after"])
+        .allow_methods(config.cors_methods.iter().map(String::as_str));
+
+    if config.cors_origins.iter().any(|origin| origin == "*") {
+        Some(cors.allow_any_origin())
+    } else {
+        Some(cors.allow_origins(config.cors_origins.iter().map(String::as_str)))
+    }
+}
+
 fn with_config(
     config: Config,
 ) -> impl Filter<Extract = (Config,), Error = std::convert::Infallible> + Clone {
@@ -0,0 +1,316 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use warp::{Filter, Rejection};
+
+use crate::auth::{with_client, Auth, Client, Route};
+use crate::config::Config;
+use crate::errors::{RateLimitedError, TooManySimulationsError};
+
+/// How long clients are asked to wait when no simulation slot is available.
+const BUSY_RETRY_AFTER: u64 = 1;
+
+/// Clients are tracked individually, stop tracking idle ones once there are this many.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Limits on the number of simulations running at once and on the rate of requests, both
+/// globally and per client. A client is identified by its API key, or its IP address if it
+/// doesn't use a known one.
+pub struct Limits {
+    simulations: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queued: usize,
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    client_max_simulations: Option<usize>,
+    client_rate_limit: Option<u32>,
+    clients: DashMap<String, ClientLimits>,
+}
+
+struct ClientLimits {
+    simulations: Option<Arc<Semaphore>>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl ClientLimits {
+    fn is_idle(&mut self) -> bool {
+        let simulations_idle = self
+            .simulations
+            .as_ref()
+            .map_or(true, |simulations| Arc::strong_count(simulations) == 1);
+        let rate_limiter_idle = self
+            .rate_limiter
+            .as_mut()
+            .map_or(true, |rate_limiter| rate_limiter.is_full());
+        simulations_idle && rate_limiter_idle
+    }
+}
+
+/// Held for as long as a request runs, releasing its simulation slots when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    _global: OwnedSemaphorePermit,
+    _client: Option<OwnedSemaphorePermit>,
+}
+
+/// Decrements the queue length when dropped, including when the waiting request is cancelled.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Self {
+        Limits {
+            simulations: Arc::new(Semaphore::new(config.max_concurrent_simulations)),
+            queued: AtomicUsize::new(0),
+            max_queued: config.max_queued_simulations,
+            rate_limiter: config
+                .rate_limit
+                .map(|rate| Mutex::new(RateLimiter::new(rate))),
+            client_max_simulations: config.client_max_concurrent_simulations,
+            client_rate_limit: config.client_rate_limit,
+            clients: DashMap::new(),
+        }
+    }
+
+    /// Waits for a simulation slot for `client`, queueing the request if all slots are taken.
+    /// `rate_limit` overrides the default rate limit of clients, if set.
+    pub async fn acquire(
+        &self,
+        client: &str,
+        rate_limit: Option<u32>,
+    ) -> Result<Permit, Rejection> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter
+                .lock()
+                .unwrap()
+                .try_acquire()
+                .map_err(|retry_after| warp::reject::custom(RateLimitedError(retry_after)))?;
+        }
+
+        let client_simulations = self.acquire_client(client, rate_limit)?;
+        let client_permit =
+            match client_simulations {
+                Some(simulations) => Some(simulations.try_acquire_owned().map_err(|_| {
+                    warp::reject::custom(TooManySimulationsError(BUSY_RETRY_AFTER))
+                })?),
+                None => None,
+            };
+
+        let global_permit = match self.simulations.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => {
+                let queued = Queued(&self.queued);
+                if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+                    return Err(warp::reject::custom(TooManySimulationsError(
+                        BUSY_RETRY_AFTER,
+                    )));
+                }
+                let permit = self.simulations.clone().acquire_owned().await;
+                drop(queued);
+                permit.expect("simulations semaphore is never closed")
+            }
+            Err(TryAcquireError::Closed) => unreachable!("simulations semaphore is never closed"),
+        };
+
+        Ok(Permit {
+            _global: global_permit,
+            _client: client_permit,
+        })
+    }
+
+    /// Applies the rate limit of `client`, returning its own simulation slots if it has any.
+    fn acquire_client(
+        &self,
+        client: &str,
+        rate_limit: Option<u32>,
+    ) -> Result<Option<Arc<Semaphore>>, Rejection> {
+        let rate_limit = rate_limit.or(self.client_rate_limit);
+        if rate_limit.is_none() && self.client_max_simulations.is_none() {
+            return Ok(None);
+        }
+
+        if self.clients.len() >= MAX_TRACKED_CLIENTS {
+            self.clients.retain(|_, limits| !limits.is_idle());
+        }
+
+        let mut limits = self
+            .clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientLimits {
+                simulations: self
+                    .client_max_simulations
+                    .map(|max| Arc::new(Semaphore::new(max))),
+                rate_limiter: rate_limit.map(RateLimiter::new),
+            });
+
+        if let Some(rate_limiter) = &mut limits.rate_limiter {
+            rate_limiter
+                .try_acquire()
+                .map_err(|retry_after| warp::reject::custom(RateLimitedError(retry_after)))?;
+        }
+
+        Ok(limits.simulations.clone())
+    }
+}
+
+/// Authenticates the request like `with_client`, then holds a simulation slot for its client
+/// until it's handled, rejecting it with a `429 Too Many Requests` if none is available and the
+/// queue is full. Authenticated clients are identified by their API key, others by their IP
+/// address, which only happens when authentication is disabled.
+pub fn with_permit(
+    limits: Arc<Limits>,
+    auth: Arc<Auth>,
+    route: Route,
+) -> impl Filter<Extract = (Client,), Error = Rejection> + Clone {
+    with_client(auth, route).and(warp::addr::remote()).and_then(
+        move |client: Client, addr: Option<std::net::SocketAddr>| {
+            let limits = limits.clone();
+            async move {
+                let id = match addr {
+                    _ if client.is_authenticated() => format!("key:{}", client.label()),
+                    Some(addr) => format!("ip:{}", addr.ip()),
+                    None => "unknown".to_string(),
+                };
+                let permit = limits.acquire(&id, client.rate_limit()).await?;
+                Ok::<_, Rejection>(client.with_permit(permit))
+            }
+        },
+    )
+}
+
+/// A token bucket allowing `rate` requests per second, with bursts of up to `rate` requests.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// Takes a token, or returns how many seconds to wait for the next one.
+    fn try_acquire(&mut self) -> Result<(), u64> {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if self.rate == 0.0 {
+            Err(u64::MAX)
+        } else {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            Err(wait.as_secs() + u64::from(wait.subsec_nanos() > 0))
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, RateLimiter};
+    use crate::config::{config, Config};
+
+    fn limits(config: Config) -> Limits {
+        Limits::new(&config)
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut rate_limiter = RateLimiter::new(2);
+
+        assert_eq!(rate_limiter.try_acquire(), Ok(()));
+        assert_eq!(rate_limiter.try_acquire(), Ok(()));
+        assert_eq!(rate_limiter.try_acquire(), Err(1));
+    }
+
+    #[tokio::test]
+    async fn test_limits_global_rate_limit() {
+        let limits = limits(Config {
+            rate_limit: Some(1),
+            ..config()
+        });
+
+        assert!(limits.acquire("a", None).await.is_ok());
+        assert!(limits.acquire("b", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_limits_client_rate_limit() {
+        let limits = limits(Config {
+            client_rate_limit: Some(1),
+            ..config()
+        });
+
+        assert!(limits.acquire("a", None).await.is_ok());
+        assert!(limits.acquire("a", None).await.is_err());
+        assert!(limits.acquire("b", None).await.is_ok());
+        assert!(limits.acquire("c", Some(2)).await.is_ok());
+        assert!(limits.acquire("c", Some(2)).await.is_ok());
+        assert!(limits.acquire("c", Some(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_limits_client_concurrency() {
+        let limits = limits(Config {
+            client_max_concurrent_simulations: Some(1),
+            ..config()
+        });
+
+        let permit = limits.acquire("a", None).await.unwrap();
+        assert!(limits.acquire("a", None).await.is_err());
+        assert!(limits.acquire("b", None).await.is_ok());
+
+        drop(permit);
+        assert!(limits.acquire("a", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_limits_queue() {
+        let limits = std::sync::Arc::new(limits(Config {
+            max_concurrent_simulations: 1,
+            max_queued_simulations: 1,
+            ..config()
+        }));
+
+        let permit = limits.acquire("a", None).await.unwrap();
+
+        let queued = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.acquire("b", None).await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        while limits.queued.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // The queue is full.
+        assert!(limits.acquire("c", None).await.is_err());
+
+        drop(permit);
+        assert!(queued.await.unwrap());
+    }
+}
@@ -1,50 +1,203 @@
-use std::{env, sync::Arc};
-
-use dashmap::DashMap;
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
 
 use enso_temper::{
-    config::config, errors::handle_rejection, simulate_routes, SharedSimulationState,
+    auth::API_KEY_HEADER,
+    config::{args, load, LogFormat},
+    cors,
+    errors::handle_rejection,
+    health, metrics, ready,
+    request_id::with_request_id,
+    sessions, simulate_routes, SharedSimulationState,
 };
-use warp::Filter;
+use futures::FutureExt;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use warp::{log::Info, Filter, Reply};
 
 #[tokio::main]
 async fn main() {
-    if env::var_os("RUST_LOG").is_none() {
-        // Set `RUST_LOG=ts::api=debug` to see debug logs, this only shows access logs.
-        env::set_var("RUST_LOG", "ts::api=info");
+    let args = args();
+    let print_config = args.print_config;
+    let config = match load(args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    if print_config {
+        print!("{}", config.to_redacted_toml());
+        return;
     }
-    pretty_env_logger::init();
 
-    let config = config();
+    // Defaults to `ts::api=info` which shows access logs and the duration of each phase of the
+    // simulations, set `--log ts::api=debug` to see debug logs.
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log))
+        .with_span_events(FmtSpan::CLOSE);
+    match config.log_format {
+        LogFormat::Json => subscriber.json().with_current_span(true).init(),
+        LogFormat::Text => subscriber.init(),
+    }
 
     let port = config.port;
-    let api_key = config.clone().api_key;
+    let bind_address = config.bind_address;
 
     let api_base = warp::path("api").and(warp::path("v1"));
 
-    let api_base = if let Some(api_key) = api_key {
-        log::info!(
+    let shared_state = Arc::new(SharedSimulationState::new(&config));
+
+    if let Some(sessions_file) = config.sessions_file.as_ref().filter(|path| path.exists()) {
+        match sessions::restore(sessions_file, &config, &shared_state).await {
+            Ok(restored) => tracing::info!(
+                target: "ts::api",
+                "Restored {restored} stateful simulations"
+            ),
+            Err(err) => tracing::error!(
+                target: "ts::api",
+                "Failed to restore stateful simulations: {err:?}"
+            ),
+        }
+    }
+
+    if shared_state.auth.is_enabled() {
+        tracing::info!(
             target: "ts::api",
             "Running with API key protection"
         );
-        let api_key_filter = warp::header::exact("X-API-KEY", Box::leak(api_key.into_boxed_str()));
-        api_base.and(api_key_filter).boxed()
-    } else {
-        api_base.boxed()
+    }
+
+    // Same fields as `warp::log`, with the label of the API key used. Logged within the request
+    // span, so it also carries the request id.
+    let access_log = {
+        let state = shared_state.clone();
+        warp::log::custom(move |info: Info| {
+            let key = info
+                .request_headers()
+                .get(API_KEY_HEADER)
+                .and_then(|key| key.to_str().ok());
+            tracing::info!(
+                target: "ts::api",
+                remote_addr = %info.remote_addr().map_or("-".to_string(), |addr| addr.to_string()),
+                version = ?info.version(),
+                status = info.status().as_u16(),
+                referer = info.referer().unwrap_or("-"),
+                user_agent = info.user_agent().unwrap_or("-"),
+                elapsed_ms = info.elapsed().as_millis() as u64,
+                key = %state.auth.label(key),
+                "request completed"
+            );
+        })
     };
 
-    let shared_state = Arc::new(SharedSimulationState {
-        evms: Arc::new(DashMap::new()),
-    });
+    let tls = config.tls_cert.clone().zip(config.tls_key.clone());
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+    let sessions_file = config.sessions_file.clone();
 
-    let routes = api_base
-        .and(simulate_routes(config, shared_state))
-        .recover(handle_rejection)
-        .with(warp::log("ts::api"));
+    let cors = cors(&config);
+    let routes = health()
+        .or(ready(config.clone()))
+        .or(metrics())
+        .or(api_base.and(simulate_routes(config, shared_state.clone())));
+    // Every log about a request happens within its span, which carries its id. CORS applies
+    // last, so that browsers can read error responses too.
+    let routes = with_request_id(routes);
+    let routes = match cors {
+        Some(cors) => routes
+            .with(cors)
+            .recover(handle_rejection)
+            .map(boxed_reply)
+            .boxed(),
+        None => routes.map(boxed_reply).boxed(),
+    };
+    let routes = routes
+        .with(access_log)
+        .with(warp::log::custom(metrics::record_request))
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                target: "ts::api",
+                "request",
+                method = %info.method(),
+                path = %info.path(),
+                request_id = tracing::field::Empty,
+            )
+        }));
+
+    // Stop accepting connections on shutdown, but let in-flight requests finish.
+    let shutdown = shutdown_signal().shared();
+    let server: Pin<Box<dyn Future<Output = ()>>> = match tls {
+        Some((cert, key)) => {
+            let (addr, server) = warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .bind_with_graceful_shutdown((bind_address, port), shutdown.clone());
+            tracing::info!(
+                target: "ts::api",
+                "Starting server on https://{addr}"
+            );
+            Box::pin(server)
+        }
+        None => {
+            let (addr, server) = warp::serve(routes)
+                .bind_with_graceful_shutdown((bind_address, port), shutdown.clone());
+            tracing::info!(
+                target: "ts::api",
+                "Starting server on http://{addr}"
+            );
+            Box::pin(server)
+        }
+    };
+
+    tokio::select! {
+        _ = server => {}
+        _ = shutdown.then(|_| tokio::time::sleep(shutdown_timeout)) => {
+            tracing::warn!(
+                target: "ts::api",
+                "In-flight requests didn't finish within {}s, aborting them",
+                shutdown_timeout.as_secs()
+            );
+        }
+    }
+
+    if let Some(sessions_file) = sessions_file {
+        match sessions::save(&sessions_file, &shared_state) {
+            Ok(saved) => tracing::info!(
+                target: "ts::api",
+                "Saved {saved} stateful simulations to {}",
+                sessions_file.display()
+            ),
+            Err(err) => tracing::error!(
+                target: "ts::api",
+                "Failed to save stateful simulations: {err:?}"
+            ),
+        }
+    }
+}
+
+fn boxed_reply(reply: impl Reply + 'static) -> Box<dyn Reply> {
+    Box::new(reply)
+}
+
+/// Completes on SIGTERM, as sent by orchestrators, or on Ctrl-C.
+async fn shutdown_signal() {
+    let terminate = async {
+        #[cfg(unix)]
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
 
-    log::info!(
+    tracing::info!(
         target: "ts::api",
-        "Starting server on port {port}"
+        "Shutting down, waiting for in-flight requests to finish"
     );
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
 }
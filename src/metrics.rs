@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+use uuid::Uuid;
+use warp::{log::Info, Rejection, Reply};
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "temper_http_requests_total",
+        "Number of HTTP requests handled, by route and status code.",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "temper_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route.",
+        &["route"],
+        exponential_buckets(0.005, 2.0, 14).unwrap()
+    )
+    .unwrap()
+});
+
+static SIMULATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "temper_simulations_total",
+        "Number of simulated transactions, by chain and outcome (success, revert or error).",
+        &["chain_id", "outcome"]
+    )
+    .unwrap()
+});
+
+static GAS_USED: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "temper_simulation_gas_used",
+        "Gas used by simulated transactions, by chain.",
+        &["chain_id"],
+        exponential_buckets(21_000.0, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+static FORKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "temper_forks_total",
+        "Number of forks set up against an upstream RPC node, by chain and outcome.",
+        &["chain_id", "outcome"]
+    )
+    .unwrap()
+});
+
+static FORK_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "temper_fork_duration_seconds",
+        "Time spent setting up forks against an upstream RPC node, by chain.",
+        &["chain_id"],
+        exponential_buckets(0.05, 2.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+static FORK_RPC_CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "temper_fork_rpc_calls_total",
+        "Number of calls forks made to upstream RPC nodes, state fetches included, by method and outcome.",
+        &["method", "outcome"]
+    )
+    .unwrap()
+});
+
+static FORK_RPC_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "temper_fork_rpc_duration_seconds",
+        "Time taken by upstream RPC nodes to answer the calls of forks, by method.",
+        &["method"],
+        exponential_buckets(0.005, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+static FORK_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "temper_fork_cache_requests_total",
+        "Number of cached forks at a pinned block, by whether they had to fetch any state.",
+        &["result"]
+    )
+    .unwrap()
+});
+
+static STATEFUL_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "temper_stateful_sessions",
+        "Number of live stateful simulation sessions."
+    )
+    .unwrap()
+});
+
+/// Records an HTTP request, meant to be used with `warp::log::custom`.
+pub fn record_request(info: Info) {
+    let route = route_label(info.path());
+    HTTP_REQUESTS
+        .with_label_values(&[route, info.status().as_str()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+pub fn record_simulation(chain_id: u64, success: bool, gas_used: u64) {
+    let chain_id = chain_id.to_string();
+    let outcome = if success { "success" } else { "revert" };
+    SIMULATIONS.with_label_values(&[&chain_id, outcome]).inc();
+    GAS_USED
+        .with_label_values(&[&chain_id])
+        .observe(gas_used as f64);
+}
+
+pub fn record_simulation_error(chain_id: u64) {
+    SIMULATIONS
+        .with_label_values(&[&chain_id.to_string(), "error"])
+        .inc();
+}
+
+/// Records a fork setup, `chain_id` is `None` if the upstream never told us which chain it is.
+pub fn record_fork(chain_id: Option<u64>, success: bool, elapsed: Duration) {
+    let chain_id = chain_id.map_or("unknown".to_string(), |id| id.to_string());
+    let outcome = if success { "success" } else { "error" };
+    FORKS.with_label_values(&[&chain_id, outcome]).inc();
+    FORK_DURATION
+        .with_label_values(&[&chain_id])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Records a call made by a fork to its RPC node.
+pub fn record_fork_rpc(method: &str, success: bool, elapsed: Duration) {
+    let method = method_label(method);
+    let outcome = if success { "success" } else { "error" };
+    FORK_RPC_CALLS.with_label_values(&[method, outcome]).inc();
+    FORK_RPC_DURATION
+        .with_label_values(&[method])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn record_fork_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    FORK_CACHE.with_label_values(&[result]).inc();
+}
+
+pub fn stateful_session_started() {
+    STATEFUL_SESSIONS.inc();
+}
+
+pub fn stateful_session_ended() {
+    STATEFUL_SESSIONS.dec();
+}
+
+pub async fn metrics() -> Result<impl Reply, Rejection> {
+    let encoder = TextEncoder::new();
+    let body = encoder
+        .encode_to_string(&prometheus::gather())
+        .unwrap_or_default();
+
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        encoder.format_type(),
+    ))
+}
+
+/// The methods the fork backend calls, any other being labelled `other`.
+const METHODS: &[&str] = &[
+    "eth_chainId",
+    "eth_blockNumber",
+    "eth_gasPrice",
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getBalance",
+    "eth_getTransactionCount",
+    "eth_getCode",
+    "eth_getStorageAt",
+    "eth_getTransactionByHash",
+    "eth_getTransactionReceipt",
+];
+
+fn method_label(method: &str) -> &'static str {
+    METHODS
+        .iter()
+        .find(|known| **known == method)
+        .copied()
+        .unwrap_or("other")
+}
+
+/// The routes served, with `{id}` standing for a UUID and `{chainId}` for a chain ID.
+const ROUTES: &[&str] = &[
+    "/health",
+    "/ready",
+    "/metrics",
+    "/api/v1/simulate",
+    "/api/v1/simulate-bundle",
+    "/api/v1/simulate-safe",
+    "/api/v1/simulate-user-operation",
+    "/api/v1/simulate-stateful",
+    "/api/v1/simulate-stateful/{id}",
+    "/api/v1/simulate-stateful/{id}/state",
+    "/api/v1/simulations/{id}",
+    "/api/v1/simulations/{id}/rerun",
+    "/api/v1/replay",
+    "/api/v1/replay-block",
+    "/api/v1/simulate-in-block",
+    "/api/v1/rpc/{chainId}",
+];
+
+/// Collapses request paths into the routes they match, so that ids in the path or requests to
+/// unknown paths, whatever their status, don't each create a new time series.
+fn route_label(path: &str) -> &'static str {
+    let template = path
+        .split('/')
+        .map(|segment| {
+            if Uuid::parse_str(segment).is_ok() {
+                "{id}"
+            } else if !segment.is_empty() && segment.parse::<u64>().is_ok() {
+                "{chainId}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    ROUTES
+        .iter()
+        .find(|route| **route == template)
+        .copied()
+        .unwrap_or("unmatched")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_route_label() {
+        assert_eq!(super::route_label("/api/v1/simulate"), "/api/v1/simulate");
+        assert_eq!(
+            super::route_label("/api/v1/simulate-stateful/aeb708a5-81d7-4126-a0b5-0f2a78b3830e"),
+            "/api/v1/simulate-stateful/{id}"
+        );
+        assert_eq!(super::route_label("/api/v1/rpc/1"), "/api/v1/rpc/{chainId}");
+        // Whether they're answered with a 404, a 401 or a 403 from CORS.
+        assert_eq!(super::route_label("/some/random/path"), "unmatched");
+        assert_eq!(super::route_label("/api/v1/simulate/extra"), "unmatched");
+        assert_eq!(super::route_label("/api/v1/rpc/mainnet"), "unmatched");
+    }
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(super::method_label("eth_getStorageAt"), "eth_getStorageAt");
+        assert_eq!(super::method_label("eth_anythingElse"), "other");
+    }
+}
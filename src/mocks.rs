@@ -0,0 +1,379 @@
+use ethers::abi::{self, Address, Hash, Token, Uint};
+use ethers::types::Bytes;
+use serde::{Deserialize, Serialize};
+use warp::Rejection;
+
+use crate::evm::Evm;
+use crate::permits::{erc2612_digest, permit2_digest, permit2_transfer_digest};
+use crate::simulation::PermissiveUint;
+
+/// The `ecrecover` precompile.
+const ECRECOVER: u64 = 1;
+
+/// A contract, or precompile, replaced by a mock answering calls to it without running its code.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Mock {
+    /// `ecrecover` recovers `signer` from any signature, so that checks of signatures from it
+    /// pass.
+    Ecrecover { signer: Address },
+    /// A Chainlink price feed whose latest round has `answer`, updated at the block timestamp.
+    #[serde(rename_all = "camelCase")]
+    PriceFeed {
+        address: Address,
+        answer: PermissiveUint,
+        decimals: Option<u8>,
+    },
+    /// `ecrecover` recovers `signer` from any signature of `digest`, other signatures being
+    /// checked as usual.
+    Signature { signer: Address, digest: Hash },
+    /// The ERC-2612 `permit` of `token` accepts any signature of `owner` approving `spender`.
+    Permit {
+        token: Address,
+        owner: Address,
+        spender: Address,
+        value: PermissiveUint,
+        deadline: PermissiveUint,
+    },
+    /// The `permit` of Permit2 accepts any signature of `owner` approving `spender`, with the
+    /// current nonce of the allowance unless `nonce` is given.
+    #[serde(rename_all = "camelCase")]
+    Permit2 {
+        owner: Address,
+        token: Address,
+        amount: PermissiveUint,
+        expiration: u64,
+        spender: Address,
+        sig_deadline: PermissiveUint,
+        nonce: Option<u64>,
+    },
+    /// The `permitTransferFrom` of Permit2 called by `spender` accepts any signature of `owner`
+    /// letting it transfer `amount` of `token`.
+    #[serde(rename_all = "camelCase")]
+    Permit2Transfer {
+        owner: Address,
+        token: Address,
+        amount: PermissiveUint,
+        spender: Address,
+        nonce: PermissiveUint,
+        deadline: PermissiveUint,
+    },
+    /// An ERC-1271 wallet at `address` accepting any signature.
+    Erc1271 { address: Address },
+    /// Calls to `address` with data starting with `data`, all of them if it's not set, return
+    /// `return_data`, or revert with it.
+    #[serde(rename_all = "camelCase")]
+    Call {
+        address: Address,
+        data: Option<Bytes>,
+        #[serde(default)]
+        return_data: Bytes,
+        #[serde(default)]
+        revert: bool,
+    },
+}
+
+/// A call answered by a mock: its address, the prefix of the data it's called with, what it
+/// returns and whether it reverts.
+type MockedCall = (Address, Bytes, Bytes, bool);
+
+impl Mock {
+    pub(crate) fn calls(&self, timestamp: Uint) -> Vec<MockedCall> {
+        let selector = |selector: [u8; 4]| Bytes::from(selector.to_vec());
+        let returned = |tokens: &[Token]| Bytes::from(abi::encode(tokens));
+
+        match self {
+            Mock::Ecrecover { signer } => vec![(
+                Address::from_low_u64_be(ECRECOVER),
+                Bytes::default(),
+                returned(&[Token::Address(*signer)]),
+                false,
+            )],
+            // The digest comes first in the data of `ecrecover`, followed by the signature.
+            Mock::Signature { signer, digest } => vec![(
+                Address::from_low_u64_be(ECRECOVER),
+                Bytes::from(digest.as_bytes().to_vec()),
+                returned(&[Token::Address(*signer)]),
+                false,
+            )],
+            // Their digests depend on the state, see `apply_mocks`.
+            Mock::Permit { .. } | Mock::Permit2 { .. } | Mock::Permit2Transfer { .. } => vec![],
+            Mock::PriceFeed {
+                address,
+                answer,
+                decimals,
+            } => {
+                let answer = Token::Int(Uint::from(*answer));
+                let round = Token::Uint(Uint::one());
+                let timestamp = Token::Uint(timestamp);
+
+                let mut calls = vec![
+                    // latestRoundData()
+                    (
+                        *address,
+                        selector([0xfe, 0xaf, 0x96, 0x8c]),
+                        returned(&[
+                            round.clone(),
+                            answer.clone(),
+                            timestamp.clone(),
+                            timestamp.clone(),
+                            round,
+                        ]),
+                        false,
+                    ),
+                    // latestAnswer()
+                    (
+                        *address,
+                        selector([0x50, 0xd2, 0x5b, 0xcd]),
+                        returned(&[answer]),
+                        false,
+                    ),
+                    // latestTimestamp()
+                    (
+                        *address,
+                        selector([0x82, 0x05, 0xbf, 0x6a]),
+                        returned(&[timestamp]),
+                        false,
+                    ),
+                ];
+                if let Some(decimals) = decimals {
+                    // decimals()
+                    calls.push((
+                        *address,
+                        selector([0x31, 0x3c, 0xe5, 0x67]),
+                        returned(&[Token::Uint((*decimals).into())]),
+                        false,
+                    ));
+                }
+                calls
+            }
+            Mock::Erc1271 { address } => {
+                // The magic values returned for valid signatures are the selectors themselves.
+                let magic = |selector: [u8; 4]| {
+                    let mut word = [0; 32];
+                    word[..4].copy_from_slice(&selector);
+                    Bytes::from(word.to_vec())
+                };
+                // isValidSignature(bytes32,bytes) and the legacy isValidSignature(bytes,bytes)
+                [[0x16, 0x26, 0xba, 0x7e], [0x20, 0xc1, 0x3b, 0x0b]]
+                    .into_iter()
+                    .map(|call| (*address, selector(call), magic(call), false))
+                    .collect()
+            }
+            Mock::Call {
+                address,
+                data,
+                return_data,
+                revert,
+            } => vec![(
+                *address,
+                data.clone().unwrap_or_default(),
+                return_data.clone(),
+                *revert,
+            )],
+        }
+    }
+}
+
+/// Installs `mocks`, which answer calls until the end of the simulation, or of the bundle or
+/// stateful simulation. Price feeds keep answering with the current block timestamp as it moves.
+pub fn apply_mocks(evm: &mut Evm, mocks: &[Mock]) -> Result<(), Rejection> {
+    let timestamp = evm.get_block_timestamp();
+    for mock in mocks {
+        if let Mock::PriceFeed { .. } = mock {
+            evm.mock_price_feed(mock.clone());
+            continue;
+        }
+
+        let mock = signature(evm, mock)?.unwrap_or_else(|| mock.clone());
+        for (address, data, returned, revert) in mock.calls(timestamp) {
+            evm.mock_call(address, data, returned, revert);
+        }
+    }
+
+    Ok(())
+}
+
+/// The signature `mock` accepts if it's a permit, found from the current state.
+fn signature(evm: &mut Evm, mock: &Mock) -> Result<Option<Mock>, Rejection> {
+    let (signer, digest) = match mock {
+        Mock::Permit {
+            token,
+            owner,
+            spender,
+            value,
+            deadline,
+        } => (
+            *owner,
+            erc2612_digest(
+                evm,
+                *token,
+                *owner,
+                *spender,
+                (*value).into(),
+                (*deadline).into(),
+            )?,
+        ),
+        Mock::Permit2 {
+            owner,
+            token,
+            amount,
+            expiration,
+            spender,
+            sig_deadline,
+            nonce,
+        } => (
+            *owner,
+            permit2_digest(
+                evm,
+                *owner,
+                *token,
+                (*amount).into(),
+                *expiration,
+                *spender,
+                (*sig_deadline).into(),
+                *nonce,
+            )?,
+        ),
+        Mock::Permit2Transfer {
+            owner,
+            token,
+            amount,
+            spender,
+            nonce,
+            deadline,
+        } => (
+            *owner,
+            permit2_transfer_digest(
+                evm,
+                *token,
+                (*amount).into(),
+                *spender,
+                (*nonce).into(),
+                (*deadline).into(),
+            )?,
+        ),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Mock::Signature { signer, digest }))
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::{Address, Hash, Uint};
+    use ethers::types::Bytes;
+
+    use super::Mock;
+    use crate::simulation::PermissiveUint;
+
+    #[test]
+    fn test_mock_deserialize() {
+        let mock: Mock = serde_json::from_value(serde_json::json!({
+            "kind": "priceFeed",
+            "address": Address::repeat_byte(0x11),
+            "answer": "200000000000",
+            "decimals": 8,
+        }))
+        .unwrap();
+        assert!(matches!(
+            mock,
+            Mock::PriceFeed {
+                decimals: Some(8),
+                ..
+            }
+        ));
+
+        let mock: Mock = serde_json::from_value(serde_json::json!({
+            "kind": "call",
+            "address": Address::repeat_byte(0x11),
+            "data": "0x12345678",
+        }))
+        .unwrap();
+        assert_eq!(
+            mock,
+            Mock::Call {
+                address: Address::repeat_byte(0x11),
+                data: Some(Bytes::from(vec![0x12, 0x34, 0x56, 0x78])),
+                return_data: Bytes::default(),
+                revert: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ecrecover_calls() {
+        let signer = Address::repeat_byte(0x22);
+        let calls = Mock::Ecrecover { signer }.calls(Uint::zero());
+
+        assert_eq!(calls.len(), 1);
+        let (address, data, returned, revert) = &calls[0];
+        assert_eq!(*address, Address::from_low_u64_be(1));
+        assert!(data.is_empty());
+        assert_eq!(returned[..12], [0; 12]);
+        assert_eq!(returned[12..], signer.0);
+        assert!(!revert);
+    }
+
+    #[test]
+    fn test_signature_calls() {
+        let digest = Hash::repeat_byte(0x33);
+        let calls = Mock::Signature {
+            signer: Address::repeat_byte(0x22),
+            digest,
+        }
+        .calls(Uint::zero());
+
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].1[..], digest[..]);
+
+        let permit: Mock = serde_json::from_value(serde_json::json!({
+            "kind": "permit2",
+            "owner": Address::repeat_byte(0x11),
+            "token": Address::repeat_byte(0x22),
+            "amount": "1000",
+            "expiration": 1_700_000_000,
+            "spender": Address::repeat_byte(0x33),
+            "sigDeadline": "1800000000",
+        }))
+        .unwrap();
+        assert!(matches!(permit, Mock::Permit2 { nonce: None, .. }));
+        assert!(permit.calls(Uint::zero()).is_empty());
+    }
+
+    #[test]
+    fn test_erc1271_calls() {
+        let calls = Mock::Erc1271 {
+            address: Address::repeat_byte(0x11),
+        }
+        .calls(Uint::zero());
+
+        assert_eq!(calls.len(), 2);
+        for (_, data, returned, _) in calls {
+            assert_eq!(returned[..4], data[..]);
+            assert_eq!(returned[4..], [0; 28]);
+        }
+    }
+
+    #[test]
+    fn test_price_feed_calls() {
+        let mock = Mock::PriceFeed {
+            address: Address::repeat_byte(0x11),
+            answer: PermissiveUint(Uint::from(42)),
+            decimals: None,
+        };
+        let calls = mock.calls(Uint::from(1_700_000_000));
+
+        // No decimals() without decimals.
+        assert_eq!(calls.len(), 3);
+
+        let (_, data, returned, _) = &calls[0];
+        assert_eq!(data[..], [0xfe, 0xaf, 0x96, 0x8c]);
+        assert_eq!(returned.len(), 5 * 32);
+        assert_eq!(Uint::from_big_endian(&returned[32..64]), Uint::from(42));
+        assert_eq!(
+            Uint::from_big_endian(&returned[96..128]),
+            Uint::from(1_700_000_000)
+        );
+    }
+}
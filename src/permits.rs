@@ -0,0 +1,288 @@
+use ethers::abi::{self, Address, Hash, Token, Uint};
+use ethers::types::{Bytes, H160, H256};
+use ethers::utils::keccak256;
+use warp::Rejection;
+
+use crate::errors::InvalidPermitError;
+use crate::evm::Evm;
+
+/// Permit2, deployed at the same address on every chain.
+pub const PERMIT2: Address = H160([
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x22, 0xd4, 0x73, 0x03, 0x0f, 0x11, 0x6d, 0xde, 0xe9, 0xf6, 0xb4,
+    0x3a, 0xc7, 0x8b, 0xa3,
+]);
+
+/// The digest `owner` signs to approve `spender` with the ERC-2612 `permit` of `token`, with its
+/// current nonce.
+pub fn erc2612_digest(
+    evm: &mut Evm,
+    token: Address,
+    owner: Address,
+    spender: Address,
+    value: Uint,
+    deadline: Uint,
+) -> Result<Hash, Rejection> {
+    let domain_separator = domain_separator(evm, token)?;
+    // nonces(address)
+    let nonce = read(
+        evm,
+        token,
+        [0x7e, 0xce, 0xbe, 0x00],
+        &[Token::Address(owner)],
+        0,
+    )?;
+
+    Ok(digest(
+        domain_separator,
+        erc2612_hash(owner, spender, value, nonce, deadline),
+    ))
+}
+
+/// The digest `owner` signs to approve `spender` through the `permit` of Permit2, with the
+/// current nonce of the allowance unless `nonce` is given.
+#[allow(clippy::too_many_arguments)]
+pub fn permit2_digest(
+    evm: &mut Evm,
+    owner: Address,
+    token: Address,
+    amount: Uint,
+    expiration: u64,
+    spender: Address,
+    sig_deadline: Uint,
+    nonce: Option<u64>,
+) -> Result<Hash, Rejection> {
+    let domain_separator = domain_separator(evm, PERMIT2)?;
+    let nonce = match nonce {
+        Some(nonce) => nonce.into(),
+        // allowance(address,address,address), returning the amount, expiration and nonce.
+        None => read(
+            evm,
+            PERMIT2,
+            [0x92, 0x7d, 0xa1, 0x05],
+            &[
+                Token::Address(owner),
+                Token::Address(token),
+                Token::Address(spender),
+            ],
+            2,
+        )?,
+    };
+
+    Ok(digest(
+        domain_separator,
+        permit_single_hash(
+            token,
+            amount,
+            expiration.into(),
+            nonce,
+            spender,
+            sig_deadline,
+        ),
+    ))
+}
+
+/// The digest signed to let `spender` transfer tokens through `permitTransferFrom` of Permit2.
+pub fn permit2_transfer_digest(
+    evm: &mut Evm,
+    token: Address,
+    amount: Uint,
+    spender: Address,
+    nonce: Uint,
+    deadline: Uint,
+) -> Result<Hash, Rejection> {
+    let domain_separator = domain_separator(evm, PERMIT2)?;
+
+    Ok(digest(
+        domain_separator,
+        permit_transfer_from_hash(token, amount, spender, nonce, deadline),
+    ))
+}
+
+fn domain_separator(evm: &mut Evm, address: Address) -> Result<Hash, Rejection> {
+    // DOMAIN_SEPARATOR()
+    let separator = read(evm, address, [0x36, 0x44, 0xe5, 0x15], &[], 0)?;
+    Ok(word(separator))
+}
+
+/// The `index`th word returned by calling `selector` of `address` with `arguments`.
+fn read(
+    evm: &mut Evm,
+    address: Address,
+    selector: [u8; 4],
+    arguments: &[Token],
+    index: usize,
+) -> Result<Uint, Rejection> {
+    let data: Bytes = [selector.as_slice(), &abi::encode(arguments)]
+        .concat()
+        .into();
+    let (returned, _) = evm.probe(address, data)?;
+    returned
+        .get(index * 32..(index + 1) * 32)
+        .map(Uint::from_big_endian)
+        .ok_or_else(|| warp::reject::custom(InvalidPermitError))
+}
+
+/// The EIP-712 digest of a message whose struct hashes to `struct_hash`.
+fn digest(domain_separator: Hash, struct_hash: Hash) -> Hash {
+    H256(keccak256(
+        [
+            &[0x19, 0x01],
+            domain_separator.as_bytes(),
+            struct_hash.as_bytes(),
+        ]
+        .concat(),
+    ))
+}
+
+fn hash_struct(type_: &str, fields: Vec<Token>) -> Hash {
+    let type_hash = Token::FixedBytes(keccak256(type_).to_vec());
+    H256(keccak256(abi::encode(&[vec![type_hash], fields].concat())))
+}
+
+fn erc2612_hash(
+    owner: Address,
+    spender: Address,
+    value: Uint,
+    nonce: Uint,
+    deadline: Uint,
+) -> Hash {
+    hash_struct(
+        "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)",
+        vec![
+            Token::Address(owner),
+            Token::Address(spender),
+            Token::Uint(value),
+            Token::Uint(nonce),
+            Token::Uint(deadline),
+        ],
+    )
+}
+
+fn permit_single_hash(
+    token: Address,
+    amount: Uint,
+    expiration: Uint,
+    nonce: Uint,
+    spender: Address,
+    sig_deadline: Uint,
+) -> Hash {
+    let details = hash_struct(
+        "PermitDetails(address token,uint160 amount,uint48 expiration,uint48 nonce)",
+        vec![
+            Token::Address(token),
+            Token::Uint(amount),
+            Token::Uint(expiration),
+            Token::Uint(nonce),
+        ],
+    );
+    hash_struct(
+        "PermitSingle(PermitDetails details,address spender,uint256 sigDeadline)\
+         PermitDetails(address token,uint160 amount,uint48 expiration,uint48 nonce)",
+        vec![
+            Token::FixedBytes(details.as_bytes().to_vec()),
+            Token::Address(spender),
+            Token::Uint(sig_deadline),
+        ],
+    )
+}
+
+fn permit_transfer_from_hash(
+    token: Address,
+    amount: Uint,
+    spender: Address,
+    nonce: Uint,
+    deadline: Uint,
+) -> Hash {
+    let permitted = hash_struct(
+        "TokenPermissions(address token,uint256 amount)",
+        vec![Token::Address(token), Token::Uint(amount)],
+    );
+    hash_struct(
+        "PermitTransferFrom(TokenPermissions permitted,address spender,uint256 nonce,\
+         uint256 deadline)TokenPermissions(address token,uint256 amount)",
+        vec![
+            Token::FixedBytes(permitted.as_bytes().to_vec()),
+            Token::Address(spender),
+            Token::Uint(nonce),
+            Token::Uint(deadline),
+        ],
+    )
+}
+
+fn word(value: Uint) -> Hash {
+    let mut word = Hash::zero();
+    value.to_big_endian(word.as_bytes_mut());
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::abi::{Address, Hash, Uint};
+
+    use super::{digest, erc2612_hash, permit_single_hash, permit_transfer_from_hash, PERMIT2};
+
+    fn hash(hash: &str) -> Hash {
+        Hash::from_str(hash).unwrap()
+    }
+
+    // The domain separator of Permit2 on mainnet.
+    fn permit2_domain() -> Hash {
+        hash("0x866a5aba21966af95d6c7ab78eb2b2fc913915c28be3b9aa07cc04ff903e3f28")
+    }
+
+    #[test]
+    fn test_permit2_address() {
+        assert_eq!(
+            PERMIT2,
+            Address::from_str("0x000000000022D473030F116dDEE9F6B43aC78BA3").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_erc2612_digest() {
+        // USDC on mainnet.
+        let domain = hash("0x06c37168a7db5138defc7866392bb87a741f9b3d104deb5094588ce041cae335");
+        let struct_hash = erc2612_hash(
+            Address::repeat_byte(0x33),
+            Address::repeat_byte(0x22),
+            Uint::from(1000),
+            Uint::zero(),
+            Uint::from(1_800_000_000),
+        );
+
+        assert_eq!(
+            digest(domain, struct_hash),
+            hash("0x30a39b77e88c1ca7eaaa70612085e94bf85e633bfbf7547e96f9fc5c211f2f96")
+        );
+    }
+
+    #[test]
+    fn test_permit2_digests() {
+        let single = permit_single_hash(
+            Address::repeat_byte(0x11),
+            Uint::from(1000),
+            Uint::from(1_700_000_000),
+            Uint::from(3),
+            Address::repeat_byte(0x22),
+            Uint::from(1_800_000_000),
+        );
+        assert_eq!(
+            digest(permit2_domain(), single),
+            hash("0x6247b3cbce359edd191422dd175784a8963a82fe3570efb9859418616164784e")
+        );
+
+        let transfer = permit_transfer_from_hash(
+            Address::repeat_byte(0x11),
+            Uint::from(1000),
+            Address::repeat_byte(0x22),
+            Uint::from(7),
+            Uint::from(1_800_000_000),
+        );
+        assert_eq!(
+            digest(permit2_domain(), transfer),
+            hash("0x8d5ebea7abc31b391ded9332d4d1dba78192f4f05d63b6a6f9b3a5f13fd1fe94")
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! A relay between forks and the RPC nodes they're set up against.
+//!
+//! The fork backend builds its own provider from the fork URL, fetching state lazily while
+//! simulations run, so its calls can only be observed by pointing it at a local URL which
+//! forwards them. Every call is counted and timed, and each fork learns how much state it had to
+//! fetch rather than read from the cache.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use ethers::providers::{Http, HttpClientError, JsonRpcClient};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use tokio::sync::watch;
+use uuid::Uuid;
+use warp::reply::Json;
+use warp::{Filter, Rejection};
+
+use crate::errors::ForkError;
+use crate::metrics;
+
+/// The methods the fork backend reads accounts and storage with, which the cache answers when it
+/// can.
+const STATE_METHODS: &[&str] = &[
+    "eth_getBalance",
+    "eth_getTransactionCount",
+    "eth_getCode",
+    "eth_getStorageAt",
+];
+
+/// The forks being relayed, by the id in the path of their relay URL.
+static FORKS: Lazy<DashMap<Uuid, Arc<Upstream>>> = Lazy::new(DashMap::new);
+
+/// The address the relay listens on. It runs on a runtime of its own, so that it outlives
+/// whichever runtime first needed it, as each test has its own.
+static ADDRESS: Lazy<SocketAddr> = Lazy::new(|| {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build the runtime of the RPC relay");
+        runtime.block_on(async move {
+            let routes = warp::path::param::<Uuid>()
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(warp::body::json())
+                .and_then(relay);
+            let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+            let _ = sender.send(address);
+            server.await;
+        });
+    });
+    receiver.recv().expect("the RPC relay failed to start")
+});
+
+struct Upstream {
+    provider: Http,
+    /// How many calls reading state were made to the RPC node.
+    fetches: AtomicU64,
+    /// Set once the fork is dropped, to abandon the calls it's still waiting on.
+    closed: watch::Sender<bool>,
+}
+
+/// A fork relayed to its RPC node, until dropped.
+pub struct RelayedFork {
+    id: Uuid,
+    upstream: Arc<Upstream>,
+    /// Whether the fork is at a pinned block with caching enabled, so that reading state should
+    /// be answered by the cache.
+    cached: bool,
+}
+
+impl RelayedFork {
+    /// Relays the calls made to the URL of the returned fork to `fork_url`.
+    pub fn new(fork_url: &str, cached: bool) -> Result<Self, ForkError> {
+        let provider = fork_url
+            .parse::<Http>()
+            .map_err(|err| ForkError(err.into()))?;
+        let upstream = Arc::new(Upstream {
+            provider,
+            fetches: AtomicU64::new(0),
+            closed: watch::channel(false).0,
+        });
+        let id = Uuid::new_v4();
+        FORKS.insert(id, upstream.clone());
+
+        Ok(RelayedFork {
+            id,
+            upstream,
+            cached,
+        })
+    }
+
+    /// The URL to set the fork up against.
+    pub fn url(&self) -> String {
+        format!("http://{}/{}", *ADDRESS, self.id)
+    }
+}
+
+impl Drop for RelayedFork {
+    fn drop(&mut self) {
+        FORKS.remove(&self.id);
+        // A fork whose setup timed out is dropped while its backend still runs on the blocking
+        // pool, which then fails rather than holding its thread until the RPC node answers.
+        self.upstream.closed.send_replace(true);
+        // Only known once the fork is done with, as state is fetched as simulations need it.
+        if self.cached {
+            metrics::record_fork_cache(self.upstream.fetches.load(Ordering::Relaxed) == 0);
+        }
+    }
+}
+
+/// Forwards a JSON-RPC call to the RPC node of the fork it was made for.
+async fn relay(id: Uuid, request: Value) -> Result<Json, Rejection> {
+    let upstream = FORKS
+        .get(&id)
+        .map(|upstream| upstream.clone())
+        .ok_or_else(warp::reject::not_found)?;
+
+    let method = request["method"].as_str().unwrap_or_default();
+    let params = request
+        .get("params")
+        .cloned()
+        .unwrap_or(Value::Array(Vec::new()));
+    let mut closed = upstream.closed.subscribe();
+    if *closed.borrow() {
+        return Err(warp::reject::not_found());
+    }
+
+    let start = Instant::now();
+    let result = tokio::select! {
+        result = upstream.provider.request::<_, Value>(method, params) => result,
+        _ = closed.changed() => return Err(warp::reject::not_found()),
+    };
+    metrics::record_fork_rpc(method, result.is_ok(), start.elapsed());
+    if STATE_METHODS.contains(&method) {
+        upstream.fetches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let response = match result {
+        Ok(result) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": result,
+        }),
+        Err(HttpClientError::JsonRpcError(err)) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": {
+                "code": err.code,
+                "message": err.message,
+                "data": err.data,
+            },
+        }),
+        Err(err) => {
+            // Failures to reach the node mention its URL, which may hold credentials.
+            let message = match err {
+                HttpClientError::ReqwestError(_) => "failed to reach the RPC node".to_string(),
+                err => err.to_string(),
+            };
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "error": {
+                    "code": -32603,
+                    "message": message,
+                },
+            })
+        }
+    };
+
+    Ok(warp::reply::json(&response))
+}
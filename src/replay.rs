@@ -0,0 +1,492 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ethers::providers::{Http, Middleware, Provider, ProviderError};
+use ethers::types::{Block, Bytes, Log, Transaction, TransactionReceipt, TxHash, U256};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use revm::interpreter::InstructionResult;
+use serde::{Deserialize, Serialize};
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::auth::Client;
+use crate::config::Config;
+use crate::errors::{
+    BlockNotFoundError, ContractCreationNotSupportedError, EvmError, ForkError, ForkTimeoutError,
+    IncorrectChainIdError, InvalidTransactionIndexError, NoURLForChainIdError,
+    TransactionNotFoundError, TransactionPendingError,
+};
+use crate::evm::{CallRawResult, Evm};
+use crate::hardfork::Schedule;
+use crate::metrics;
+use crate::simulation::{respond, run, PermissiveUint, SimulationRequest, SimulationResponse};
+use crate::SharedSimulationState;
+
+/// How many receipts are fetched at once when replaying transactions.
+const MAX_CONCURRENT_RECEIPTS: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayRequest {
+    pub chain_id: u64,
+    pub tx_hash: TxHash,
+    /// Run the transaction right on top of the parent block, without the transactions which
+    /// preceded it in its block.
+    pub skip_preceding: Option<bool>,
+    pub format_trace: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayBlockRequest {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub format_trace: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayBlockResponse {
+    pub block_number: u64,
+    pub transactions: Vec<ReplayedTransaction>,
+    /// How many transactions diverged from their receipts.
+    pub divergent: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertRequest {
+    pub block_number: u64,
+    /// The position of the transaction in the block, the transactions from this one on are
+    /// moved down by one.
+    pub index: usize,
+    /// The transaction to insert, its block number and timestamp are those of the block.
+    pub transaction: SimulationRequest,
+    /// Run the transactions after the inserted one too, to see how it affects them.
+    pub replay_remaining: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertResponse {
+    pub simulation: SimulationResponse,
+    /// The transactions after the inserted one, empty unless `replayRemaining` is set.
+    pub remaining: Vec<ReplayedTransaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayedTransaction {
+    pub tx_hash: TxHash,
+    pub success: bool,
+    pub gas_used: u64,
+    pub logs: Vec<Log>,
+    pub exit_reason: InstructionResult,
+    pub return_data: Bytes,
+    pub formatted_trace: Option<String>,
+    /// How the results differ from those in the receipt of the transaction, if they do.
+    pub divergences: Vec<Divergence>,
+    /// Why the transaction couldn't run at all, for instance because a transaction before it
+    /// diverged. It then didn't change the state, and counts as divergent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Divergence {
+    pub field: DivergenceField,
+    pub on_chain: u64,
+    pub simulated: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DivergenceField {
+    /// 1 for success, 0 for failure.
+    Status,
+    GasUsed,
+    LogCount,
+}
+
+pub async fn replay(
+    client: Client,
+    request: ReplayRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    client.check_chain(request.chain_id)?;
+
+    let fork_url = config
+        .fork_url_for(request.chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
+    let provider = Provider::<Http>::try_from(fork_url.as_str())
+        .map_err(|err| warp::reject::custom(ForkError(err.into())))?;
+    let timeout = Duration::from_secs(config.fork_timeout);
+
+    let target = rpc(timeout, provider.get_transaction(request.tx_hash))
+        .await?
+        .ok_or_else(|| warp::reject::custom(TransactionNotFoundError))?;
+    let block_number = target
+        .block_number
+        .ok_or_else(|| warp::reject::custom(TransactionPendingError))?
+        .as_u64();
+    if target.to.is_none() {
+        return Err(warp::reject::custom(ContractCreationNotSupportedError));
+    }
+
+    let block = rpc(timeout, provider.get_block_with_txs(block_number))
+        .await?
+        .ok_or_else(|| warp::reject::custom(TransactionNotFoundError))?;
+
+    let mut evm = fork_parent(&block, request.chain_id, fork_url, &config).await?;
+
+    if request.skip_preceding.unwrap_or_default() {
+        // The sender may have sent some of the skipped transactions, which its nonce counts.
+        evm.override_account(target.from, None, Some(target.nonce.as_u64()), None, None)?;
+    } else {
+        for transaction in block
+            .transactions
+            .iter()
+            .take_while(|transaction| transaction.hash != target.hash)
+        {
+            apply(&mut evm, transaction, false).await?;
+        }
+    }
+
+    let transaction = SimulationRequest {
+        format_trace: request.format_trace,
+        hardfork: Some(evm.get_hardfork()),
+        ..simulation_request(&target, &block, request.chain_id)
+    };
+    // Run as it was mined, paying for its gas, rather than as a simulation would.
+    evm.select_hardfork(None)?;
+    let result = evm
+        .transact(&target, false, request.format_trace.unwrap_or_default())
+        .await
+        .map_err(|err| {
+            metrics::record_simulation_error(request.chain_id);
+            err
+        })?;
+    metrics::record_simulation(request.chain_id, result.success, result.gas_used);
+    let (response, state_diff) = respond(result);
+    state
+        .simulations
+        .insert(&client, transaction, response.clone(), state_diff);
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn replay_block(
+    client: Client,
+    request: ReplayBlockRequest,
+    config: Config,
+) -> Result<Json, Rejection> {
+    client.check_chain(request.chain_id)?;
+
+    let fork_url = config
+        .fork_url_for(request.chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
+    let provider = Provider::<Http>::try_from(fork_url.as_str())
+        .map_err(|err| warp::reject::custom(ForkError(err.into())))?;
+    let timeout = Duration::from_secs(config.fork_timeout);
+
+    let block = rpc(timeout, provider.get_block_with_txs(request.block_number))
+        .await?
+        .ok_or_else(|| warp::reject::custom(BlockNotFoundError))?;
+    let receipts = receipts(&provider, &block.transactions, timeout).await?;
+
+    let mut evm = fork_parent(&block, request.chain_id, fork_url, &config).await?;
+
+    let transactions = replay_all(
+        &mut evm,
+        &block.transactions,
+        receipts,
+        request.format_trace.unwrap_or_default(),
+    )
+    .await?;
+
+    let response = ReplayBlockResponse {
+        block_number: request.block_number,
+        divergent: transactions
+            .iter()
+            .filter(|transaction| {
+                !transaction.divergences.is_empty() || transaction.error.is_some()
+            })
+            .count(),
+        transactions,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+pub async fn insert(
+    client: Client,
+    request: InsertRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let chain_id = request.transaction.chain_id;
+    client.check_chain(chain_id)?;
+
+    let fork_url = config
+        .fork_url_for(chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
+    let provider = Provider::<Http>::try_from(fork_url.as_str())
+        .map_err(|err| warp::reject::custom(ForkError(err.into())))?;
+    let timeout = Duration::from_secs(config.fork_timeout);
+
+    let block = rpc(timeout, provider.get_block_with_txs(request.block_number))
+        .await?
+        .ok_or_else(|| warp::reject::custom(BlockNotFoundError))?;
+    if request.index > block.transactions.len() {
+        return Err(warp::reject::custom(InvalidTransactionIndexError));
+    }
+    let (preceding, remaining) = block.transactions.split_at(request.index);
+
+    let replay_remaining = request.replay_remaining.unwrap_or_default();
+    let receipts = if replay_remaining {
+        receipts(&provider, remaining, timeout).await?
+    } else {
+        Vec::new()
+    };
+
+    let mut evm = fork_parent(&block, chain_id, fork_url, &config).await?;
+
+    for transaction in preceding {
+        apply(&mut evm, transaction, false).await?;
+    }
+
+    let transaction = SimulationRequest {
+        block_number: Some(request.block_number),
+        block_timestamp: Some(block.timestamp.as_u64()),
+        // Only the inserted transaction runs with the hard fork it may ask for.
+        hardfork: request.transaction.hardfork.or(Some(evm.get_hardfork())),
+        ..request.transaction
+    };
+    evm.set_gas_limit(transaction.gas_limit);
+    // The transaction only needs to be committed for the remaining ones to see its changes.
+    let (simulation, state_diff) = run(
+        &mut evm,
+        &state.token_layouts,
+        transaction.clone(),
+        replay_remaining,
+    )
+    .await?;
+    state
+        .simulations
+        .insert(&client, transaction, simulation.clone(), state_diff);
+
+    let remaining = if replay_remaining {
+        replay_all(&mut evm, remaining, receipts, false).await?
+    } else {
+        Vec::new()
+    };
+
+    let response = InsertResponse {
+        simulation,
+        remaining,
+    };
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Runs `transactions` again in order, comparing their results to their `receipts`.
+/// Transactions pay their fees as they did on chain, so that the ones after them see the same
+/// balances.
+async fn replay_all(
+    evm: &mut Evm,
+    transactions: &[Transaction],
+    receipts: Vec<Option<TransactionReceipt>>,
+    format_trace: bool,
+) -> Result<Vec<ReplayedTransaction>, Rejection> {
+    let mut replayed = Vec::with_capacity(transactions.len());
+    for (transaction, receipt) in transactions.iter().zip(receipts) {
+        // Transactions the EVM refuses to run don't stop the ones after them, but failing to
+        // fetch the state does.
+        let result = match apply(evm, transaction, format_trace).await {
+            Ok(result) => result,
+            Err(rejection) => match rejection.find::<EvmError>() {
+                Some(EvmError(err)) => {
+                    let divergences = receipt
+                        .map(|receipt| divergences(&receipt, false, 0, 0))
+                        .unwrap_or_default();
+                    replayed.push(ReplayedTransaction {
+                        tx_hash: transaction.hash,
+                        success: false,
+                        gas_used: 0,
+                        logs: Vec::new(),
+                        exit_reason: InstructionResult::FatalExternalError,
+                        return_data: Bytes::default(),
+                        formatted_trace: None,
+                        divergences,
+                        error: Some(err.to_string()),
+                    });
+                    continue;
+                }
+                None => return Err(rejection),
+            },
+        };
+
+        let divergences = receipt
+            .map(|receipt| {
+                divergences(&receipt, result.success, result.gas_used, result.logs.len())
+            })
+            .unwrap_or_default();
+        replayed.push(ReplayedTransaction {
+            tx_hash: transaction.hash,
+            success: result.success,
+            gas_used: result.gas_used,
+            logs: result.logs,
+            exit_reason: result.exit_reason,
+            return_data: result.return_data,
+            formatted_trace: result.formatted_trace,
+            divergences,
+            error: None,
+        });
+    }
+
+    Ok(replayed)
+}
+
+/// Fetches the receipts of `transactions`, `MAX_CONCURRENT_RECEIPTS` at a time so that large
+/// blocks don't flood the node.
+async fn receipts(
+    provider: &Provider<Http>,
+    transactions: &[Transaction],
+    timeout: Duration,
+) -> Result<Vec<Option<TransactionReceipt>>, Rejection> {
+    rpc(
+        timeout,
+        stream::iter(transactions)
+            .map(|transaction| provider.get_transaction_receipt(transaction.hash))
+            .buffered(MAX_CONCURRENT_RECEIPTS)
+            .try_collect(),
+    )
+    .await
+}
+
+/// The results of a replayed transaction which differ from those of its receipt.
+fn divergences(
+    receipt: &TransactionReceipt,
+    success: bool,
+    gas_used: u64,
+    log_count: usize,
+) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    let mut compare = |field, on_chain: Option<u64>, simulated: u64| {
+        if let Some(on_chain) = on_chain.filter(|on_chain| *on_chain != simulated) {
+            divergences.push(Divergence {
+                field,
+                on_chain,
+                simulated,
+            });
+        }
+    };
+
+    // Receipts of transactions from before Byzantium have no status.
+    compare(
+        DivergenceField::Status,
+        receipt.status.map(|status| status.as_u64()),
+        u64::from(success),
+    );
+    compare(
+        DivergenceField::GasUsed,
+        receipt.gas_used.map(|gas_used| gas_used.as_u64()),
+        gas_used,
+    );
+    compare(
+        DivergenceField::LogCount,
+        Some(receipt.logs.len() as u64),
+        log_count as u64,
+    );
+
+    divergences
+}
+
+/// Forks the chain at the parent of `block`, with the environment and rules of `block`, so that
+/// its transactions can be run again.
+pub(crate) async fn fork_parent<TX>(
+    block: &Block<TX>,
+    chain_id: u64,
+    fork_url: String,
+    config: &Config,
+) -> Result<Evm, Rejection> {
+    let number = block
+        .number
+        .ok_or_else(|| warp::reject::custom(TransactionPendingError))?
+        .as_u64();
+    let parent_number = number.saturating_sub(1);
+
+    let mut evm = Evm::new(
+        None,
+        fork_url,
+        Some(parent_number),
+        block.gas_limit.as_u64(),
+        true,
+        config.fork_cache,
+        config.etherscan_key.clone(),
+        Duration::from_secs(config.fork_timeout),
+    )
+    .await?;
+
+    if evm.get_chain_id() != U256::from(chain_id) {
+        return Err(warp::reject::custom(IncorrectChainIdError()));
+    }
+
+    evm.set_block_header(block);
+    // The rules of the block itself, which may be the first of a hard fork.
+    evm.set_schedule(Schedule::new(config, chain_id, None), None)?;
+
+    Ok(evm)
+}
+
+/// Runs a mined transaction again and commits its changes, whether it succeeds or not.
+pub(crate) async fn apply(
+    evm: &mut Evm,
+    transaction: &Transaction,
+    format_trace: bool,
+) -> Result<CallRawResult, Rejection> {
+    // With the rules of its block, even after a transaction asking for another hard fork.
+    evm.select_hardfork(None)?;
+    Ok(evm.transact(transaction, true, format_trace).await?)
+}
+
+/// The simulation of a mined call on top of the parent of its block, so that it can be retrieved
+/// and rerun like any other.
+fn simulation_request(
+    transaction: &Transaction,
+    block: &Block<Transaction>,
+    chain_id: u64,
+) -> SimulationRequest {
+    SimulationRequest {
+        chain_id,
+        from: transaction.from,
+        to: transaction.to.unwrap_or_default(),
+        data: Some(transaction.input.clone()),
+        gas_limit: transaction.gas.as_u64(),
+        value: Some(PermissiveUint(transaction.value)),
+        access_list: transaction.access_list.clone(),
+        block_number: block.number.map(|number| number.as_u64().saturating_sub(1)),
+        block_timestamp: Some(block.timestamp.as_u64()),
+        state_overrides: None,
+        token_overrides: None,
+        mocks: None,
+        hardfork: None,
+        blob_versioned_hashes: None,
+        max_fee_per_blob_gas: None,
+        format_trace: None,
+    }
+}
+
+/// Waits for an RPC call to the fork URL, for at most `timeout`.
+pub(crate) async fn rpc<T>(
+    timeout: Duration,
+    call: impl Future<Output = Result<T, ProviderError>>,
+) -> Result<T, Rejection> {
+    match tokio::time::timeout(timeout, call).await {
+        Ok(result) => result.map_err(|err| warp::reject::custom(ForkError(err.into()))),
+        Err(_) => Err(warp::reject::custom(ForkTimeoutError)),
+    }
+}
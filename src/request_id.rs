@@ -0,0 +1,55 @@
+use std::convert::Infallible;
+
+use uuid::Uuid;
+use warp::http::{HeaderMap, HeaderValue};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::errors::rejection_reply;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Request ids sent by clients longer than this are replaced by generated ones.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The id of the request, taken from its `X-Request-Id` header or generated, and recorded on the
+/// current span so that it's part of every log about the request.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        let id = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        tracing::Span::current().record("request_id", id.as_str());
+        id
+    })
+}
+
+/// Handles the rejections of `filter` and echoes the request id in the `X-Request-Id` header of
+/// every response, and in the body of errors.
+pub fn with_request_id<F, R>(
+    filter: F,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    let filter = filter
+        .map(|reply: R| Ok(reply.into_response()))
+        .or_else(|rejection| async move { Ok::<_, Infallible>((Err(rejection),)) });
+
+    request_id()
+        .and(filter)
+        .map(|id: String, result: Result<Response, Rejection>| {
+            let mut response = match result {
+                Ok(response) => response,
+                Err(rejection) => rejection_reply(&rejection, Some(id.clone())),
+            };
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            response
+        })
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ethers::abi::{self, Address, Hash, ParamType, Token, Uint};
+use ethers::types::{Bytes, H256};
+use foundry_evm::CallKind;
+use serde::{Deserialize, Serialize};
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::auth::Client;
+use crate::config::Config;
+use crate::errors::InvalidSafeError;
+use crate::evm::{Evm, StorageOverride};
+use crate::simulation::{execute, fork_for, respond, SimulationRequest};
+use crate::SharedSimulationState;
+
+/// The slot of the threshold of a Safe, after its singleton, modules, owners and owner count.
+const THRESHOLD_SLOT: u64 = 4;
+
+/// A transaction proposed to a Safe, `from` being the Safe, to simulate before its owners sign it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SafeSimulationRequest {
+    #[serde(flatten)]
+    pub transaction: SimulationRequest,
+    #[serde(default)]
+    pub operation: Operation,
+}
+
+/// How a Safe makes the call of a transaction, `0` for a call and `1` for a delegate call, as
+/// for `MultiSend`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Operation {
+    #[default]
+    Call,
+    DelegateCall,
+}
+
+impl TryFrom<u8> for Operation {
+    type Error = String;
+
+    fn try_from(operation: u8) -> Result<Self, Self::Error> {
+        match operation {
+            0 => Ok(Operation::Call),
+            1 => Ok(Operation::DelegateCall),
+            _ => Err(format!("unknown operation {operation}")),
+        }
+    }
+}
+
+impl From<Operation> for u8 {
+    fn from(operation: Operation) -> Self {
+        operation as u8
+    }
+}
+
+/// Simulates `execTransaction` of the Safe with the transaction of `request`, as if signed by
+/// enough of its owners, returning what its call returned.
+pub async fn simulate_safe(
+    client: Client,
+    request: SafeSimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let SafeSimulationRequest {
+        transaction,
+        operation,
+    } = request;
+    let mut evm = fork_for(&client, &transaction, &config).await?;
+
+    let safe = transaction.from;
+    let owner = first_owner(&mut evm, safe)?;
+
+    // With a threshold of one, an owner sending the transaction itself approves it.
+    evm.override_account(
+        safe,
+        None,
+        None,
+        None,
+        Some(StorageOverride {
+            slots: HashMap::from([(Hash::from_low_u64_be(THRESHOLD_SLOT), Uint::one())]),
+            diff: true,
+        }),
+    )?;
+
+    let to = transaction.to;
+    let kind = match operation {
+        Operation::Call => CallKind::Call,
+        Operation::DelegateCall => CallKind::DelegateCall,
+    };
+    let exec = SimulationRequest {
+        from: owner,
+        to: safe,
+        value: None,
+        data: Some(exec_transaction(&transaction, operation, owner)),
+        ..transaction
+    };
+
+    let result = execute(&mut evm, &state.token_layouts, exec, false).await?;
+    let output = result.output_of(kind, to);
+    let (mut response, _) = respond(result);
+    if let Some(output) = output {
+        response.return_data = output;
+    }
+
+    Ok(warp::reply::json(&response))
+}
+
+/// The first owner of `safe`, failing if it isn't a Safe.
+fn first_owner(evm: &mut Evm, safe: Address) -> Result<Address, Rejection> {
+    // getOwners()
+    let (returned, _) = evm.probe(safe, Bytes::from(vec![0xa0, 0xe6, 0x7e, 0x2b]))?;
+    let owners = abi::decode(&[ParamType::Array(Box::new(ParamType::Address))], &returned).ok();
+
+    match owners.as_deref() {
+        Some([Token::Array(owners)]) => owners
+            .first()
+            .and_then(|owner| owner.clone().into_address())
+            .ok_or_else(|| warp::reject::custom(InvalidSafeError)),
+        _ => Err(warp::reject::custom(InvalidSafeError)),
+    }
+}
+
+/// The call of `execTransaction` making the call of `transaction`, without refunds and with the
+/// approval of `owner` as signature.
+fn exec_transaction(
+    transaction: &SimulationRequest,
+    operation: Operation,
+    owner: Address,
+) -> Bytes {
+    let tokens = [
+        Token::Address(transaction.to),
+        Token::Uint(transaction.value.map(Uint::from).unwrap_or_default()),
+        Token::Bytes(transaction.data.clone().unwrap_or_default().to_vec()),
+        Token::Uint(u8::from(operation).into()),
+        // safeTxGas, baseGas and gasPrice
+        Token::Uint(Uint::zero()),
+        Token::Uint(Uint::zero()),
+        Token::Uint(Uint::zero()),
+        // gasToken and refundReceiver
+        Token::Address(Address::zero()),
+        Token::Address(Address::zero()),
+        Token::Bytes(approval(owner)),
+    ];
+    [[0x6a, 0x76, 0x12, 0x02].as_slice(), &abi::encode(&tokens)]
+        .concat()
+        .into()
+}
+
+/// The signature of `owner` approving a transaction by sending it, `v` being 1 and `r` the owner.
+fn approval(owner: Address) -> Vec<u8> {
+    [H256::from(owner).as_bytes(), H256::zero().as_bytes(), &[1]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::{self, Address, ParamType, Token};
+
+    use super::{approval, exec_transaction, Operation, SafeSimulationRequest};
+
+    #[test]
+    fn test_operation_deserialize() {
+        let request = |operation: serde_json::Value| {
+            serde_json::from_value::<SafeSimulationRequest>(serde_json::json!({
+                "chainId": 1,
+                "from": Address::repeat_byte(0x11),
+                "to": Address::repeat_byte(0x22),
+                "gasLimit": 500000,
+                "operation": operation,
+            }))
+        };
+
+        assert_eq!(
+            request(1.into()).unwrap().operation,
+            Operation::DelegateCall
+        );
+        assert!(request(2.into()).is_err());
+
+        let request: SafeSimulationRequest = serde_json::from_value(serde_json::json!({
+            "chainId": 1,
+            "from": Address::repeat_byte(0x11),
+            "to": Address::repeat_byte(0x22),
+            "gasLimit": 500000,
+        }))
+        .unwrap();
+        assert_eq!(request.operation, Operation::Call);
+        assert_eq!(request.transaction.from, Address::repeat_byte(0x11));
+    }
+
+    #[test]
+    fn test_exec_transaction() {
+        let owner = Address::repeat_byte(0x33);
+        let request: SafeSimulationRequest = serde_json::from_value(serde_json::json!({
+            "chainId": 1,
+            "from": Address::repeat_byte(0x11),
+            "to": Address::repeat_byte(0x22),
+            "data": "0x12345678",
+            "value": "100",
+            "gasLimit": 500000,
+            "operation": 1,
+        }))
+        .unwrap();
+
+        let data = exec_transaction(&request.transaction, request.operation, owner);
+        assert_eq!(data[..4], [0x6a, 0x76, 0x12, 0x02]);
+
+        let tokens = abi::decode(
+            &[
+                ParamType::Address,
+                ParamType::Uint(256),
+                ParamType::Bytes,
+                ParamType::Uint(8),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Address,
+                ParamType::Address,
+                ParamType::Bytes,
+            ],
+            &data[4..],
+        )
+        .unwrap();
+        assert_eq!(tokens[0], Token::Address(Address::repeat_byte(0x22)));
+        assert_eq!(tokens[1], Token::Uint(100.into()));
+        assert_eq!(tokens[2], Token::Bytes(vec![0x12, 0x34, 0x56, 0x78]));
+        assert_eq!(tokens[3], Token::Uint(1.into()));
+        assert_eq!(tokens[9], Token::Bytes(approval(owner)));
+    }
+
+    #[test]
+    fn test_approval() {
+        let owner = Address::repeat_byte(0x33);
+        let signature = approval(owner);
+
+        assert_eq!(signature.len(), 65);
+        assert_eq!(signature[12..32], owner.0);
+        assert_eq!(signature[32..64], [0; 32]);
+        assert_eq!(signature[64], 1);
+    }
+}
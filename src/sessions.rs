@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use ethers::abi::Address;
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::evm::Evm;
+use crate::metrics;
+use crate::simulation::{
+    apply_overrides, apply_stateful, fork_stateful, SimulationRequest, StateOverride,
+    StatefulSimulationRequest,
+};
+use crate::tokens::TokenLayouts;
+use crate::SharedSimulationState;
+
+/// The requests which built a stateful simulation, so that it can be rebuilt by replaying them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionJournal {
+    /// The label of the client owning the simulation.
+    pub owner: String,
+    pub request: StatefulSimulationRequest,
+    /// What each call to `POST /simulate-stateful/{id}` or `POST /simulate-stateful/{id}/state`
+    /// applied, in order.
+    pub batches: Vec<Batch>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Batch {
+    Transactions(Vec<SimulationRequest>),
+    #[serde(rename_all = "camelCase")]
+    StateOverrides {
+        state_overrides: HashMap<Address, StateOverride>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedSession {
+    stateful_simulation_id: Uuid,
+    #[serde(flatten)]
+    journal: SessionJournal,
+}
+
+/// Writes the journals of all stateful simulations to `path`, returning how many there were.
+pub fn save(path: &Path, state: &SharedSimulationState) -> Result<usize> {
+    let sessions: Vec<PersistedSession> = state
+        .journals
+        .iter()
+        .map(|entry| PersistedSession {
+            stateful_simulation_id: *entry.key(),
+            journal: entry.value().clone(),
+        })
+        .collect();
+
+    let file = std::fs::File::create(path)
+        .wrap_err_with(|| format!("failed to create {}", path.display()))?;
+    serde_json::to_writer(file, &sessions)?;
+
+    Ok(sessions.len())
+}
+
+/// Rebuilds the stateful simulations saved to `path` by forking again and replaying their
+/// transactions, keeping their ids. Returns how many were restored.
+///
+/// Simulations which weren't pinned to a block fork from the latest one, so their state may
+/// differ from before. Those which fail to replay are dropped.
+pub async fn restore(path: &Path, config: &Config, state: &SharedSimulationState) -> Result<usize> {
+    let file =
+        std::fs::File::open(path).wrap_err_with(|| format!("failed to open {}", path.display()))?;
+    let sessions: Vec<PersistedSession> = serde_json::from_reader(file)?;
+
+    let mut restored = 0;
+    for session in sessions {
+        let id = session.stateful_simulation_id;
+        match replay(&session.journal, config, &state.token_layouts).await {
+            Ok(evm) => {
+                state.evms.insert(id, Arc::new(Mutex::new(evm)));
+                state
+                    .auth
+                    .restore_session(id, session.journal.owner.clone());
+                state.journals.insert(id, session.journal);
+                metrics::stateful_session_started();
+                restored += 1;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    target: "ts::api",
+                    "Failed to restore stateful simulation {id}: {err:?}"
+                );
+            }
+        }
+    }
+
+    Ok(restored)
+}
+
+async fn replay(journal: &SessionJournal, config: &Config, layouts: &TokenLayouts) -> Result<Evm> {
+    let mut evm = fork_stateful(&journal.request, config)
+        .await
+        .map_err(|err| eyre::eyre!("failed to fork: {err:?}"))?;
+
+    for batch in &journal.batches {
+        match batch {
+            Batch::Transactions(transactions) => {
+                apply_stateful(&mut evm, layouts, transactions.clone(), &mut Vec::new())
+                    .await
+                    .map_err(|err| eyre::eyre!("failed to replay transactions: {err:?}"))?;
+            }
+            Batch::StateOverrides { state_overrides } => {
+                apply_overrides(&mut evm, state_overrides.clone())
+                    .map_err(|err| eyre::eyre!("failed to replay state overrides: {err:?}"))?;
+            }
+        }
+    }
+
+    Ok(evm)
+}
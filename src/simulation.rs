@@ -1,8 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use dashmap::mapref::one::RefMut;
 use ethers::abi::{Address, Hash, Uint};
 use ethers::core::types::Log;
 use ethers::types::transaction::eip2930::AccessList;
@@ -15,15 +15,21 @@ use uuid::Uuid;
 use warp::reply::Json;
 use warp::Rejection;
 
+use crate::auth::Client;
 use crate::errors::{
-    IncorrectChainIdError, InvalidBlockNumbersError, MultipleChainIdsError, NoURLForChainIdError,
-    StateNotFound,
+    BlobTransactionError, IncorrectChainIdError, InvalidBlockNumbersError, MultipleChainIdsError,
+    NoURLForChainIdError, PrecompileOverrideError, StateNotFound,
 };
 use crate::evm::StorageOverride;
-use crate::SharedSimulationState;
+use crate::hardfork::{Hardfork, Schedule};
+use crate::mocks::{apply_mocks, Mock};
+use crate::sessions::{Batch, SessionJournal};
+use crate::storage::{resolve_slots, SymbolicSlot};
+use crate::tokens::{override_tokens, TokenLayouts, TokenOverride};
+use crate::{metrics, SharedSimulationState};
 
 use super::config::Config;
-use super::evm::{CallRawRequest, Evm};
+use super::evm::{CallRawRequest, CallRawResult, Evm};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -38,13 +44,23 @@ pub struct SimulationRequest {
     pub block_number: Option<u64>,
     pub block_timestamp: Option<u64>,
     pub state_overrides: Option<HashMap<Address, StateOverride>>,
+    /// ERC-20 balances and allowances to set, after `state_overrides`.
+    pub token_overrides: Option<Vec<TokenOverride>>,
+    /// Contracts and precompiles answering calls without running their code, set last.
+    pub mocks: Option<Vec<Mock>>,
+    /// Runs the transaction with the rules of this hard fork instead of those of its block.
+    pub hardfork: Option<Hardfork>,
+    /// Blob transactions (EIP-4844) need Cancun, which the EVM doesn't support yet, so requests
+    /// setting these are rejected rather than run without their blobs.
+    pub blob_versioned_hashes: Option<Vec<Hash>>,
+    pub max_fee_per_blob_gas: Option<PermissiveUint>,
     pub format_trace: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SimulationResponse {
-    pub simulation_id: u64,
+    pub simulation_id: Uuid,
     pub gas_used: u64,
     pub block_number: u64,
     pub success: bool,
@@ -53,6 +69,7 @@ pub struct SimulationResponse {
     pub logs: Vec<Log>,
     pub exit_reason: InstructionResult,
     pub return_data: Bytes,
+    pub hardfork: Hardfork,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,6 +79,7 @@ pub struct StatefulSimulationRequest {
     pub gas_limit: u64,
     pub block_number: Option<u64>,
     pub block_timestamp: Option<u64>,
+    pub hardfork: Option<Hardfork>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -70,18 +88,72 @@ pub struct StatefulSimulationResponse {
     pub stateful_simulation_id: Uuid,
 }
 
+/// The state of an account once overrides were applied to a stateful simulation.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountState {
+    pub balance: Uint,
+    pub nonce: u64,
+    pub code: Bytes,
+    /// The values of the slots which were overridden.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<Hash, Uint>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct StatefulSimulationEndResponse {
     pub success: bool,
 }
 
+/// An override of an account, with the same semantics as in geth's `eth_call`: `balance`,
+/// `nonce` and `code` replace those of the account, `state` replaces all of its storage and
+/// `stateDiff` only the given slots.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", try_from = "RawStateOverride")]
 pub struct StateOverride {
     pub balance: Option<PermissiveUint>,
     pub nonce: Option<u64>,
     pub code: Option<Bytes>,
     #[serde(flatten)]
     pub state: Option<State>,
+    /// Slots given by the position of variables, set after `state` or `stateDiff`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slots: Option<Vec<SymbolicSlot>>,
+}
+
+/// A `StateOverride` as sent, before checking that its fields don't conflict. Unknown fields are
+/// rejected rather than ignored, such as geth's `movePrecompileToAddress` which the EVM can't
+/// support as it always runs precompiles at their own address.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RawStateOverride {
+    balance: Option<PermissiveUint>,
+    nonce: Option<u64>,
+    code: Option<Bytes>,
+    state: Option<HashMap<Hash, PermissiveUint>>,
+    state_diff: Option<HashMap<Hash, PermissiveUint>>,
+    slots: Option<Vec<SymbolicSlot>>,
+}
+
+impl TryFrom<RawStateOverride> for StateOverride {
+    type Error = &'static str;
+
+    fn try_from(raw: RawStateOverride) -> Result<Self, Self::Error> {
+        let state = match (raw.state, raw.state_diff) {
+            (Some(_), Some(_)) => return Err("state and stateDiff can't both be set"),
+            (Some(state), None) => Some(State::Full { state }),
+            (None, Some(state_diff)) => Some(State::Diff { state_diff }),
+            (None, None) => None,
+        };
+
+        Ok(StateOverride {
+            balance: raw.balance,
+            nonce: raw.nonce,
+            code: raw.code,
+            state,
+            slots: raw.slots,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -113,6 +185,81 @@ impl From<State> for StorageOverride {
     }
 }
 
+/// The accounts a simulation changed, and how.
+pub type StateDiff = BTreeMap<Address, AccountDiff>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountDiff {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Change<Uint>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Change<u64>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<Hash, Change<Hash>>,
+}
+
+impl AccountDiff {
+    pub fn is_empty(&self) -> bool {
+        self.balance.is_none() && self.nonce.is_none() && self.storage.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Change<T> {
+    pub from: T,
+    pub to: T,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RerunRequest {
+    /// The block to run the simulation on, the latest one if not set.
+    pub block_number: Option<u64>,
+    pub block_timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RerunResponse {
+    pub original_simulation_id: Uuid,
+    pub simulation: SimulationResponse,
+    pub comparison: SimulationComparison,
+}
+
+/// The results of a simulation and of its rerun, side by side.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationComparison {
+    pub block_number: Comparison<u64>,
+    pub success: Comparison<bool>,
+    pub gas_used: Comparison<u64>,
+    pub logs: Comparison<Vec<Log>>,
+    /// Only known for simulations run through `POST /simulate` or rerun, left out otherwise rather
+    /// than reported as changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_diff: Option<Comparison<StateDiff>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparison<T> {
+    pub original: T,
+    pub rerun: T,
+    pub changed: bool,
+}
+
+impl<T: PartialEq> Comparison<T> {
+    fn new(original: T, rerun: T) -> Self {
+        let changed = original != rerun;
+        Comparison {
+            original,
+            rerun,
+            changed,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct CallTrace {
@@ -148,51 +295,98 @@ impl<'de> Deserialize<'de> for PermissiveUint {
     }
 }
 
-fn chain_id_to_fork_url(chain_id: u64) -> Result<String, Rejection> {
-    match chain_id {
-        // ethereum
-        1 => Ok("https://eth.llamarpc.com".to_string()),
-        5 => Ok("https://eth-goerli.g.alchemy.com/v2/demo".to_string()),
-        11155111 => Ok("https://eth-sepolia.g.alchemy.com/v2/demo".to_string()),
-        // polygon
-        137 => Ok("https://polygon-mainnet.g.alchemy.com/v2/demo".to_string()),
-        80001 => Ok("https://polygon-mumbai.g.alchemy.com/v2/demo".to_string()),
-        // avalanche
-        43114 => Ok("https://api.avax.network/ext/bc/C/rpc".to_string()),
-        43113 => Ok("https://api.avax-test.network/ext/bc/C/rpc".to_string()),
-        // fantom
-        250 => Ok("https://rpcapi.fantom.network/".to_string()),
-        4002 => Ok("https://rpc.testnet.fantom.network/".to_string()),
-        // xdai
-        100 => Ok("https://rpc.xdaichain.com/".to_string()),
-        // bsc
-        56 => Ok("https://bsc-dataseed.binance.org/".to_string()),
-        97 => Ok("https://data-seed-prebsc-1-s1.binance.org:8545/".to_string()),
-        // arbitrum
-        42161 => Ok("https://arb1.arbitrum.io/rpc".to_string()),
-        421613 => Ok("https://goerli-rollup.arbitrum.io/rpc".to_string()),
-        // optimism
-        10 => Ok("https://mainnet.optimism.io/".to_string()),
-        420 => Ok("https://goerli.optimism.io/".to_string()),
-        _ => Err(NoURLForChainIdError.into()),
+/// Applies `overrides` to the state, returning the storage slots set for each account.
+pub(crate) fn apply_overrides(
+    evm: &mut Evm,
+    overrides: HashMap<Address, StateOverride>,
+) -> Result<BTreeMap<Address, Vec<Hash>>, Rejection> {
+    // Precompiles run whatever the code at their address, so overriding it would be ignored.
+    if overrides.iter().any(|(address, state_override)| {
+        state_override.code.is_some() && evm.is_precompile(*address)
+    }) {
+        return Err(warp::reject::custom(PrecompileOverrideError));
     }
-}
 
-async fn run(
-    evm: &mut Evm,
-    transaction: SimulationRequest,
-    commit: bool,
-) -> Result<SimulationResponse, Rejection> {
-    for (address, state_override) in transaction.state_overrides.into_iter().flatten() {
+    // Everything which may fail is done before any account is overridden, so that stateful
+    // simulations aren't left with only some of the overrides.
+    let mut resolved = Vec::with_capacity(overrides.len());
+    for (address, state_override) in overrides {
+        let mut storage = state_override.state.map(StorageOverride::from);
+        if let Some(symbolic) = &state_override.slots {
+            let storage = storage.get_or_insert_with(|| StorageOverride {
+                slots: HashMap::new(),
+                diff: true,
+            });
+            resolve_slots(evm, address, symbolic, storage)?;
+        }
+        // Fetched from the fork now, so that overriding it can't fail on the fork.
+        evm.get_account(address)?;
+        resolved.push((address, state_override, storage));
+    }
+
+    let mut slots = BTreeMap::new();
+    for (address, state_override, storage) in resolved {
+        slots.insert(
+            address,
+            storage
+                .as_ref()
+                .map(|storage| storage.slots.keys().copied().collect())
+                .unwrap_or_default(),
+        );
         evm.override_account(
             address,
             state_override.balance.map(Uint::from),
             state_override.nonce,
             state_override.code,
-            state_override.state.map(StorageOverride::from),
+            storage,
         )?;
     }
 
+    Ok(slots)
+}
+
+/// Runs `transaction`, returning its response along with the changes it made to the state if
+/// they're known.
+pub(crate) async fn run(
+    evm: &mut Evm,
+    layouts: &TokenLayouts,
+    transaction: SimulationRequest,
+    commit: bool,
+) -> Result<(SimulationResponse, Option<StateDiff>), Rejection> {
+    let result = execute(evm, layouts, transaction, commit).await?;
+    Ok(respond(result))
+}
+
+/// Applies the overrides of `transaction` and runs it, returning the result of the call.
+pub(crate) async fn execute(
+    evm: &mut Evm,
+    layouts: &TokenLayouts,
+    transaction: SimulationRequest,
+    commit: bool,
+) -> Result<CallRawResult, Rejection> {
+    if transaction.blob_versioned_hashes.is_some() || transaction.max_fee_per_blob_gas.is_some() {
+        return Err(warp::reject::custom(BlobTransactionError));
+    }
+    // Bundles and stateful simulations may have moved to a block of another hard fork since
+    // the previous transaction, whose own `hardfork` only applied to it.
+    evm.select_hardfork(transaction.hardfork)?;
+
+    // Token overrides and mocks are resolved on top of the state overrides, so the three are
+    // undone together if any fails. Stateful simulations are then never left with some of them,
+    // which their journal wouldn't record.
+    tracing::info_span!(target: "ts::api", "overrides").in_scope(|| {
+        evm.atomically(|evm| {
+            apply_overrides(evm, transaction.state_overrides.unwrap_or_default())?;
+            override_tokens(
+                evm,
+                layouts,
+                transaction.chain_id,
+                transaction.token_overrides.as_deref().unwrap_or_default(),
+            )?;
+            apply_mocks(evm, transaction.mocks.as_deref().unwrap_or_default())
+        })
+    })?;
+
     let call = CallRawRequest {
         from: transaction.from,
         to: transaction.to,
@@ -202,13 +396,24 @@ async fn run(
         format_trace: transaction.format_trace.unwrap_or_default(),
     };
     let result = if commit {
-        evm.call_raw_committing(call, transaction.gas_limit).await?
+        evm.call_raw_committing(call, transaction.gas_limit).await
     } else {
-        evm.call_raw(call).await?
-    };
+        evm.call_raw(call).await
+    }
+    .map_err(|err| {
+        metrics::record_simulation_error(transaction.chain_id);
+        err
+    })?;
+    metrics::record_simulation(transaction.chain_id, result.success, result.gas_used);
 
-    Ok(SimulationResponse {
-        simulation_id: 1,
+    Ok(result)
+}
+
+/// The response to a simulation which gave `result`, along with the changes it made to the state
+/// if they're known.
+pub(crate) fn respond(result: CallRawResult) -> (SimulationResponse, Option<StateDiff>) {
+    let response = SimulationResponse {
+        simulation_id: Uuid::new_v4(),
         gas_used: result.gas_used,
         block_number: result.block_number,
         success: result.success,
@@ -223,21 +428,60 @@ async fn run(
         exit_reason: result.exit_reason,
         formatted_trace: result.formatted_trace,
         return_data: result.return_data,
-    })
+        hardfork: result.hardfork,
+    };
+
+    (response, result.state_diff)
 }
 
-pub async fn simulate(transaction: SimulationRequest, config: Config) -> Result<Json, Rejection> {
+pub async fn simulate(
+    client: Client,
+    transaction: SimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let (response, state_diff) =
+        simulate_single(&client, &state, transaction.clone(), &config).await?;
+    state
+        .simulations
+        .insert(&client, transaction, response.clone(), state_diff);
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Forks the chain of `transaction` at its block and runs it alone.
+async fn simulate_single(
+    client: &Client,
+    state: &SharedSimulationState,
+    transaction: SimulationRequest,
+    config: &Config,
+) -> Result<(SimulationResponse, Option<StateDiff>), Rejection> {
+    let mut evm = fork_for(client, &transaction, config).await?;
+    run(&mut evm, &state.token_layouts, transaction, false).await
+}
+
+/// Forks the chain of `transaction` at its block, after checking that `client` may use it.
+pub(crate) async fn fork_for(
+    client: &Client,
+    transaction: &SimulationRequest,
+    config: &Config,
+) -> Result<Evm, Rejection> {
+    client.check_chain(transaction.chain_id)?;
+
     let fork_url = config
-        .fork_url
-        .unwrap_or(chain_id_to_fork_url(transaction.chain_id)?);
+        .fork_url_for(transaction.chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
     let mut evm = Evm::new(
         None,
         fork_url,
         transaction.block_number,
         transaction.gas_limit,
         true,
-        config.etherscan_key,
-    );
+        config.fork_cache,
+        config.etherscan_key.clone(),
+        Duration::from_secs(config.fork_timeout),
+    )
+    .await?;
 
     if evm.get_chain_id() != Uint::from(transaction.chain_id) {
         return Err(warp::reject::custom(IncorrectChainIdError()));
@@ -249,30 +493,40 @@ pub async fn simulate(transaction: SimulationRequest, config: Config) -> Result<
             .expect("failed to set block timestamp");
     }
 
-    let response = run(&mut evm, transaction, false).await?;
+    evm.set_schedule(
+        Schedule::new(config, transaction.chain_id, None),
+        transaction.hardfork,
+    )?;
 
-    Ok(warp::reply::json(&response))
+    Ok(evm)
 }
 
 pub async fn simulate_bundle(
+    client: Client,
     transactions: Vec<SimulationRequest>,
     config: Config,
+    state: Arc<SharedSimulationState>,
 ) -> Result<Json, Rejection> {
     let first_chain_id = transactions[0].chain_id;
     let first_block_number = transactions[0].block_number;
     let first_block_timestamp = transactions[0].block_timestamp;
 
+    client.check_chain(first_chain_id)?;
+
     let fork_url = config
-        .fork_url
-        .unwrap_or(chain_id_to_fork_url(first_chain_id)?);
+        .fork_url_for(first_chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
     let mut evm = Evm::new(
         None,
         fork_url,
         first_block_number,
         transactions[0].gas_limit,
         true,
-        config.etherscan_key,
-    );
+        config.fork_cache,
+        config.etherscan_key.clone(),
+        Duration::from_secs(config.fork_timeout),
+    )
+    .await?;
 
     if evm.get_chain_id() != Uint::from(first_chain_id) {
         return Err(warp::reject::custom(IncorrectChainIdError()));
@@ -284,6 +538,11 @@ pub async fn simulate_bundle(
             .expect("failed to set block timestamp");
     }
 
+    evm.set_schedule(
+        Schedule::new(&config, first_chain_id, None),
+        transactions[0].hardfork,
+    )?;
+
     let mut response = Vec::with_capacity(transactions.len());
     for transaction in transactions {
         if transaction.chain_id != first_chain_id {
@@ -304,35 +563,132 @@ pub async fn simulate_bundle(
                 .await
                 .expect("Failed to set block timestamp");
         }
-        response.push(run(&mut evm, transaction, true).await?);
+        let (result, state_diff) =
+            run(&mut evm, &state.token_layouts, transaction.clone(), true).await?;
+        state
+            .simulations
+            .insert(&client, transaction, result.clone(), state_diff);
+        response.push(result);
     }
 
     Ok(warp::reply::json(&response))
 }
 
-pub async fn simulate_stateful_new(
-    stateful_simulation_request: StatefulSimulationRequest,
-    config: Config,
-    state: Arc<SharedSimulationState>,
-) -> Result<Json, Rejection> {
+/// Forks the chain a stateful simulation runs on.
+pub(crate) async fn fork_stateful(
+    stateful_simulation_request: &StatefulSimulationRequest,
+    config: &Config,
+) -> Result<Evm, Rejection> {
     let fork_url = config
-        .fork_url
-        .unwrap_or(chain_id_to_fork_url(stateful_simulation_request.chain_id)?);
+        .fork_url_for(stateful_simulation_request.chain_id)
+        .ok_or_else(|| warp::reject::custom(NoURLForChainIdError))?;
+
     let mut evm = Evm::new(
         None,
         fork_url,
         stateful_simulation_request.block_number,
         stateful_simulation_request.gas_limit,
         true,
-        config.etherscan_key,
-    );
+        config.fork_cache,
+        config.etherscan_key.clone(),
+        Duration::from_secs(config.fork_timeout),
+    )
+    .await?;
 
     if let Some(timestamp) = stateful_simulation_request.block_timestamp {
         evm.set_block_timestamp(timestamp).await?;
     }
 
+    // The hard fork requested when starting the simulation applies to all of its blocks.
+    evm.set_schedule(
+        Schedule::new(
+            config,
+            stateful_simulation_request.chain_id,
+            stateful_simulation_request.hardfork,
+        ),
+        None,
+    )?;
+
+    Ok(evm)
+}
+
+/// Runs `transactions` on top of a stateful simulation, pushing those which ran to `applied`
+/// even if a later one fails, as they have changed its state anyway.
+pub(crate) async fn apply_stateful(
+    evm: &mut Evm,
+    layouts: &TokenLayouts,
+    transactions: Vec<SimulationRequest>,
+    applied: &mut Vec<SimulationRequest>,
+) -> Result<Vec<SimulationResponse>, Rejection> {
+    let first_chain_id = transactions[0].chain_id;
+    let first_block_number = transactions[0].block_number;
+
+    let mut response = Vec::with_capacity(transactions.len());
+
+    if evm.get_chain_id() != Uint::from(first_chain_id) {
+        return Err(warp::reject::custom(IncorrectChainIdError()));
+    }
+
+    for transaction in transactions {
+        if transaction.chain_id != first_chain_id {
+            return Err(warp::reject::custom(MultipleChainIdsError()));
+        }
+        if transaction.block_number != first_block_number
+            || transaction.block_number.unwrap() != evm.get_block().as_u64()
+        {
+            let tx_block = transaction
+                .block_number
+                .expect("Transaction has no block number");
+            if transaction.block_number < first_block_number || tx_block < evm.get_block().as_u64()
+            {
+                return Err(warp::reject::custom(InvalidBlockNumbersError()));
+            }
+            evm.set_block(tx_block)
+                .await
+                .expect("Failed to set block number");
+            let block_timestamp = evm.get_block_timestamp().as_u64();
+            evm.set_block_timestamp(block_timestamp + 12)
+                .await
+                .expect("Failed to set block timestamp");
+        }
+        let (result, _) = run(evm, layouts, transaction.clone(), true).await?;
+        response.push(result);
+        applied.push(transaction);
+    }
+
+    Ok(response)
+}
+
+pub async fn simulate_stateful_new(
+    client: Client,
+    stateful_simulation_request: StatefulSimulationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    client.check_chain(stateful_simulation_request.chain_id)?;
+
+    // Reserve the session before forking, so that concurrent requests can't exceed the limit.
     let new_id = Uuid::new_v4();
+    state.auth.start_session(&client, new_id)?;
+
+    let evm = match fork_stateful(&stateful_simulation_request, &config).await {
+        Ok(evm) => evm,
+        Err(err) => {
+            state.auth.end_session(new_id);
+            return Err(err);
+        }
+    };
+
     state.evms.insert(new_id, Arc::new(Mutex::new(evm)));
+    state.journals.insert(
+        new_id,
+        SessionJournal {
+            owner: client.label().to_string(),
+            request: stateful_simulation_request,
+            batches: Vec::new(),
+        },
+    );
+    metrics::stateful_session_started();
 
     let response = StatefulSimulationResponse {
         stateful_simulation_id: new_id,
@@ -343,10 +699,16 @@ pub async fn simulate_stateful_new(
 
 pub async fn simulate_stateful_end(
     param: Uuid,
+    client: Client,
     state: Arc<SharedSimulationState>,
 ) -> Result<Json, Rejection> {
+    state.auth.check_session(&client, param)?;
+
     if state.evms.contains_key(&param) {
         state.evms.remove(&param);
+        state.journals.remove(&param);
+        state.auth.end_session(param);
+        metrics::stateful_session_ended();
         let response = StatefulSimulationEndResponse { success: true };
         Ok(warp::reply::json(&response))
     } else {
@@ -356,52 +718,135 @@ pub async fn simulate_stateful_end(
 
 pub async fn simulate_stateful(
     param: Uuid,
+    client: Client,
     transactions: Vec<SimulationRequest>,
     state: Arc<SharedSimulationState>,
 ) -> Result<Json, Rejection> {
-    let first_chain_id = transactions[0].chain_id;
-    let first_block_number = transactions[0].block_number;
+    state.auth.check_session(&client, param)?;
 
-    let mut response = Vec::with_capacity(transactions.len());
+    let evm = session(&state, param)?;
+    let mut evm = evm.lock().await;
+
+    let mut applied = Vec::new();
+    let response = apply_stateful(&mut evm, &state.token_layouts, transactions, &mut applied).await;
+
+    if let Ok(response) = &response {
+        for (transaction, result) in applied.iter().zip(response) {
+            state
+                .simulations
+                .insert(&client, transaction.clone(), result.clone(), None);
+        }
+    }
+
+    if !applied.is_empty() {
+        if let Some(mut journal) = state.journals.get_mut(&param) {
+            journal.batches.push(Batch::Transactions(applied));
+        }
+    }
 
-    // Get a mutable reference to the EVM here.
-    let evm_ref_mut: RefMut<'_, Uuid, Arc<Mutex<Evm>>> = state
+    Ok(warp::reply::json(&response?))
+}
+
+/// The EVM of the stateful simulation `id`, taken out of the map so that its shard isn't locked
+/// while waiting for the simulations already running on it.
+fn session(state: &SharedSimulationState, id: Uuid) -> Result<Arc<Mutex<Evm>>, Rejection> {
+    state
         .evms
-        .get_mut(&param)
-        .ok_or_else(warp::reject::not_found)?;
+        .get(&id)
+        .map(|evm| evm.value().clone())
+        .ok_or_else(warp::reject::not_found)
+}
+
+/// Applies `overrides` to a stateful simulation without running a transaction, returning the
+/// resulting state of the accounts they changed.
+pub async fn simulate_stateful_state(
+    param: Uuid,
+    client: Client,
+    overrides: HashMap<Address, StateOverride>,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    state.auth.check_session(&client, param)?;
 
-    // Dereference to obtain the EVM.
-    let evm = evm_ref_mut.value();
+    let evm = session(&state, param)?;
     let mut evm = evm.lock().await;
 
-    if evm.get_chain_id() != Uint::from(first_chain_id) {
-        return Err(warp::reject::custom(IncorrectChainIdError()));
+    let slots = tracing::info_span!(target: "ts::api", "overrides")
+        .in_scope(|| apply_overrides(&mut evm, overrides.clone()))?;
+
+    if let Some(mut journal) = state.journals.get_mut(&param) {
+        journal.batches.push(Batch::StateOverrides {
+            state_overrides: overrides,
+        });
     }
 
-    for transaction in transactions {
-        if transaction.chain_id != first_chain_id {
-            return Err(warp::reject::custom(MultipleChainIdsError()));
-        }
-        if transaction.block_number != first_block_number
-            || transaction.block_number.unwrap() != evm.get_block().as_u64()
-        {
-            let tx_block = transaction
-                .block_number
-                .expect("Transaction has no block number");
-            if transaction.block_number < first_block_number || tx_block < evm.get_block().as_u64()
-            {
-                return Err(warp::reject::custom(InvalidBlockNumbersError()));
-            }
-            evm.set_block(tx_block)
-                .await
-                .expect("Failed to set block number");
-            let block_timestamp = evm.get_block_timestamp().as_u64();
-            evm.set_block_timestamp(block_timestamp + 12)
-                .await
-                .expect("Failed to set block timestamp");
-        }
-        response.push(run(&mut evm, transaction, true).await?);
+    let mut response = BTreeMap::new();
+    for (address, slots) in slots {
+        let (balance, nonce, code) = evm.get_account(address)?;
+        let storage = slots
+            .into_iter()
+            .map(|slot| Ok((slot, evm.get_storage(address, slot)?)))
+            .collect::<Result<_, Rejection>>()?;
+        response.insert(
+            address,
+            AccountState {
+                balance,
+                nonce,
+                code,
+                storage,
+            },
+        );
     }
 
     Ok(warp::reply::json(&response))
 }
+
+pub async fn get_simulation(
+    param: Uuid,
+    client: Client,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let simulation = state.simulations.get(&client, param)?;
+
+    Ok(warp::reply::json(simulation.as_ref()))
+}
+
+pub async fn rerun_simulation(
+    param: Uuid,
+    client: Client,
+    rerun: RerunRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let original = state.simulations.get(&client, param)?;
+
+    let transaction = SimulationRequest {
+        block_number: rerun.block_number,
+        block_timestamp: rerun.block_timestamp,
+        ..original.request.clone()
+    };
+    let (simulation, state_diff) =
+        simulate_single(&client, &state, transaction.clone(), &config).await?;
+    state
+        .simulations
+        .insert(&client, transaction, simulation.clone(), state_diff.clone());
+
+    let comparison = SimulationComparison {
+        block_number: Comparison::new(original.response.block_number, simulation.block_number),
+        success: Comparison::new(original.response.success, simulation.success),
+        gas_used: Comparison::new(original.response.gas_used, simulation.gas_used),
+        logs: Comparison::new(original.response.logs.clone(), simulation.logs.clone()),
+        state_diff: original
+            .state_diff
+            .clone()
+            .zip(state_diff)
+            .map(|(original, rerun)| Comparison::new(original, rerun)),
+    };
+
+    let response = RerunResponse {
+        original_simulation_id: param,
+        simulation,
+        comparison,
+    };
+
+    Ok(warp::reply::json(&response))
+}
@@ -0,0 +1,350 @@
+use std::str::FromStr;
+
+use ethers::abi::{Address, Hash, Uint};
+use ethers::types::{Bytes, H256, I256};
+use ethers::utils::keccak256;
+use serde::{Deserialize, Serialize};
+use warp::Rejection;
+
+use crate::errors::InvalidStorageSlotError;
+use crate::evm::{Evm, StorageOverride};
+use crate::simulation::PermissiveUint;
+
+/// A storage slot given by the position of a Solidity variable rather than by its hash.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolicSlot {
+    /// The slot of the variable.
+    pub slot: PermissiveUint,
+    /// The mapping keys and array indexes leading to the value, outermost first.
+    #[serde(default)]
+    pub path: Vec<PathElement>,
+    /// For struct members and fixed-size array elements, how many slots after the start of the
+    /// struct or array they are.
+    #[serde(default)]
+    pub field: u64,
+    /// For values packed with others, how many bytes from the right of the slot they start.
+    #[serde(default)]
+    pub offset: u8,
+    /// For values packed with others, how many bytes they take, the rest of the slot is kept.
+    pub size: Option<u8>,
+    pub value: PermissiveUint,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PathElement {
+    #[serde(rename_all = "camelCase")]
+    Key { key: String, key_type: KeyType },
+    #[serde(rename_all = "camelCase")]
+    Index {
+        index: u64,
+        /// How many slots each element of the array takes.
+        #[serde(default = "one")]
+        element_slots: u64,
+    },
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// The Solidity type of a mapping key, which determines how it's hashed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Address,
+    Uint,
+    Int,
+    Bool,
+    /// `bytes1` to `bytes32`.
+    FixedBytes,
+    Bytes,
+    String,
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let size = |prefix: &str| s.strip_prefix(prefix).map(str::parse::<u16>);
+        match s {
+            "address" => Ok(KeyType::Address),
+            "bool" => Ok(KeyType::Bool),
+            "bytes" => Ok(KeyType::Bytes),
+            "string" => Ok(KeyType::String),
+            "uint" => Ok(KeyType::Uint),
+            "int" => Ok(KeyType::Int),
+            _ => match (size("uint"), size("int"), size("bytes")) {
+                (Some(Ok(8..=256)), _, _) => Ok(KeyType::Uint),
+                (_, Some(Ok(8..=256)), _) => Ok(KeyType::Int),
+                (_, _, Some(Ok(1..=32))) => Ok(KeyType::FixedBytes),
+                _ => Err(format!("unsupported key type {s}")),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            KeyType::Address => "address",
+            KeyType::Uint => "uint256",
+            KeyType::Int => "int256",
+            KeyType::Bool => "bool",
+            KeyType::FixedBytes => "bytes32",
+            KeyType::Bytes => "bytes",
+            KeyType::String => "string",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Serialize for KeyType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl KeyType {
+    /// How `key` is hashed with the slot of a mapping, padded to 32 bytes for value types.
+    fn encode(self, key: &str) -> Option<Vec<u8>> {
+        let word = match self {
+            KeyType::Address => H256::from(Address::from_str(key).ok()?),
+            KeyType::Uint => {
+                let value = if key.starts_with("0x") {
+                    Uint::from_str(key).ok()?
+                } else {
+                    Uint::from_dec_str(key).ok()?
+                };
+                word(value)
+            }
+            KeyType::Int => word(I256::from_dec_str(key).ok()?.into_raw()),
+            KeyType::Bool => H256::from_low_u64_be(bool::from_str(key).ok()? as u64),
+            KeyType::FixedBytes => {
+                let bytes = Bytes::from_str(key).ok()?;
+                if bytes.len() > 32 {
+                    return None;
+                }
+                let mut word = H256::zero();
+                word[..bytes.len()].copy_from_slice(&bytes);
+                word
+            }
+            KeyType::Bytes => return Some(Bytes::from_str(key).ok()?.to_vec()),
+            KeyType::String => return Some(key.as_bytes().to_vec()),
+        };
+        Some(word.as_bytes().to_vec())
+    }
+}
+
+impl SymbolicSlot {
+    /// The hash of the slot, as Solidity lays out storage.
+    pub fn resolve(&self) -> Result<Hash, InvalidStorageSlotError> {
+        let slot =
+            self.path
+                .iter()
+                .try_fold(Uint::from(self.slot), |slot, element| match element {
+                    PathElement::Key { key, key_type } => {
+                        let key = key_type.encode(key).ok_or(InvalidStorageSlotError)?;
+                        let preimage = [key.as_slice(), word(slot).as_bytes()].concat();
+                        Ok(Uint::from(keccak256(preimage)))
+                    }
+                    PathElement::Index {
+                        index,
+                        element_slots,
+                    } => {
+                        let start = Uint::from(keccak256(word(slot)));
+                        let offset = Uint::from(*index).full_mul((*element_slots).into());
+                        let offset = Uint::try_from(offset).map_err(|_| InvalidStorageSlotError)?;
+                        Ok(start.overflowing_add(offset).0)
+                    }
+                })?;
+
+        Ok(word(slot.overflowing_add(self.field.into()).0))
+    }
+
+    /// The value of the slot once this value is written to it, keeping what's packed with it in
+    /// `current`.
+    pub fn pack(&self, current: Uint) -> Result<Uint, InvalidStorageSlotError> {
+        let value = Uint::from(self.value);
+        let Some(size) = self.size else {
+            return Ok(value);
+        };
+
+        let (offset, size) = (usize::from(self.offset), usize::from(size));
+        if size == 0 || offset + size > 32 {
+            return Err(InvalidStorageSlotError);
+        }
+
+        let mask = if size == 32 {
+            Uint::MAX
+        } else {
+            (Uint::one() << (size * 8)) - Uint::one()
+        };
+        if value > mask {
+            return Err(InvalidStorageSlotError);
+        }
+
+        Ok((current & !(mask << (offset * 8))) | (value << (offset * 8)))
+    }
+}
+
+fn word(value: Uint) -> H256 {
+    let mut word = H256::zero();
+    value.to_big_endian(word.as_bytes_mut());
+    word
+}
+
+/// Adds the slots of `symbolic` to the storage override of `address`, reading what's packed with
+/// them from the current state unless all of its storage is replaced.
+pub fn resolve_slots(
+    evm: &Evm,
+    address: Address,
+    symbolic: &[SymbolicSlot],
+    storage: &mut StorageOverride,
+) -> Result<(), Rejection> {
+    for symbolic in symbolic {
+        let slot = symbolic.resolve()?;
+        let current = match storage.slots.get(&slot) {
+            Some(current) => *current,
+            None if storage.diff && symbolic.size.is_some() => evm.get_storage(address, slot)?,
+            None => Uint::zero(),
+        };
+        storage.slots.insert(slot, symbolic.pack(current)?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::abi::{Address, Hash, Uint};
+    use ethers::types::H256;
+    use ethers::utils::keccak256;
+
+    use super::{word, KeyType, PathElement, SymbolicSlot};
+    use crate::simulation::PermissiveUint;
+
+    fn symbolic(slot: u64, path: Vec<PathElement>) -> SymbolicSlot {
+        SymbolicSlot {
+            slot: PermissiveUint(slot.into()),
+            path,
+            field: 0,
+            offset: 0,
+            size: None,
+            value: PermissiveUint(1.into()),
+        }
+    }
+
+    fn key(key: &str, key_type: &str) -> PathElement {
+        PathElement::Key {
+            key: key.to_string(),
+            key_type: key_type.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_key_type() {
+        assert_eq!(KeyType::from_str("uint8"), Ok(KeyType::Uint));
+        assert_eq!(KeyType::from_str("int256"), Ok(KeyType::Int));
+        assert_eq!(KeyType::from_str("bytes4"), Ok(KeyType::FixedBytes));
+        assert_eq!(KeyType::from_str("bytes"), Ok(KeyType::Bytes));
+        assert!(KeyType::from_str("uint7").is_err());
+        assert!(KeyType::from_str("bytes33").is_err());
+        assert!(KeyType::from_str("mapping").is_err());
+    }
+
+    #[test]
+    fn test_resolve_mapping() {
+        let zero = symbolic(0, vec![key(&format!("{:?}", Address::zero()), "address")]);
+        assert_eq!(
+            zero.resolve().unwrap(),
+            Hash::from_str("0xad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5")
+                .unwrap()
+        );
+
+        // allowance[owner][spender] at slot 4, as in most ERC-20 tokens.
+        let owner = Address::repeat_byte(0x11);
+        let spender = Address::repeat_byte(0x22);
+        let nested = symbolic(
+            4,
+            vec![
+                key(&format!("{owner:?}"), "address"),
+                key(&format!("{spender:?}"), "address"),
+            ],
+        );
+        let inner = keccak256([H256::from(owner).as_bytes(), &[0; 31], &[4]].concat());
+        let expected = keccak256([H256::from(spender).as_bytes(), &inner].concat());
+        assert_eq!(nested.resolve().unwrap(), H256(expected));
+
+        let string = symbolic(2, vec![key("abc", "string")]);
+        let expected = keccak256([b"abc".as_slice(), &[0; 31], &[2]].concat());
+        assert_eq!(string.resolve().unwrap(), H256(expected));
+
+        let int = symbolic(1, vec![key("-1", "int128")]);
+        let expected = keccak256([[0xff; 32].as_slice(), &[0; 31], &[1]].concat());
+        assert_eq!(int.resolve().unwrap(), H256(expected));
+
+        assert!(symbolic(0, vec![key("0x12", "address")]).resolve().is_err());
+    }
+
+    #[test]
+    fn test_resolve_array() {
+        let start =
+            Uint::from_str("0x290decd9548b62a8d60345a988386fc84ba6bc95484008f6362f93160ef3e563")
+                .unwrap();
+
+        let element = symbolic(
+            0,
+            vec![PathElement::Index {
+                index: 2,
+                element_slots: 3,
+            }],
+        );
+        assert_eq!(element.resolve().unwrap(), word(start + Uint::from(6)));
+
+        let field = SymbolicSlot {
+            field: 1,
+            ..element
+        };
+        assert_eq!(field.resolve().unwrap(), word(start + Uint::from(7)));
+    }
+
+    #[test]
+    fn test_pack() {
+        let packed = SymbolicSlot {
+            offset: 20,
+            size: Some(1),
+            value: PermissiveUint(0xab.into()),
+            ..symbolic(0, vec![])
+        };
+        // A flag packed after an address, as in `struct { address owner; bool flag; }`.
+        let current = Uint::from_str(&format!("0x01{}01", "00".repeat(19))).unwrap();
+        assert_eq!(
+            packed.pack(current).unwrap(),
+            Uint::from_str(&format!("0xab{}01", "00".repeat(19))).unwrap()
+        );
+
+        let too_large = SymbolicSlot {
+            value: PermissiveUint(0x100.into()),
+            ..packed.clone()
+        };
+        assert!(too_large.pack(current).is_err());
+
+        let overflowing = SymbolicSlot {
+            offset: 31,
+            size: Some(2),
+            ..packed
+        };
+        assert!(overflowing.pack(current).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::Rejection;
+
+use crate::auth::Client;
+use crate::errors::SimulationNotFoundError;
+use crate::simulation::{SimulationRequest, SimulationResponse, StateDiff};
+
+/// A simulation which ran, as returned by `GET /simulations/{simulationId}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredSimulation {
+    pub request: SimulationRequest,
+    pub response: SimulationResponse,
+    /// The changes the simulation made to the state, only known for simulations which ran on
+    /// their own rather than in a bundle or stateful simulation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_diff: Option<StateDiff>,
+    /// When the simulation ran, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+struct Entry {
+    /// The label of the client which ran the simulation.
+    owner: String,
+    simulation: Arc<StoredSimulation>,
+}
+
+/// The results of the latest simulations, so that they can be retrieved by their id. Once full,
+/// the oldest ones are dropped first.
+pub struct SimulationStore {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    simulations: HashMap<Uuid, Entry>,
+    order: VecDeque<Uuid>,
+}
+
+impl SimulationStore {
+    pub fn new(capacity: usize) -> Self {
+        SimulationStore {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Keeps the result of a simulation ran by `client`.
+    pub fn insert(
+        &self,
+        client: &Client,
+        request: SimulationRequest,
+        response: SimulationResponse,
+        state_diff: Option<StateDiff>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let id = response.simulation_id;
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let entry = Entry {
+            owner: client.label().to_string(),
+            simulation: Arc::new(StoredSimulation {
+                request,
+                response,
+                state_diff,
+                created_at,
+            }),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        while inner.order.len() >= self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.simulations.remove(&oldest);
+            }
+        }
+        inner.order.push_back(id);
+        inner.simulations.insert(id, entry);
+    }
+
+    /// The simulation `id`, if `client` ran it. Clients can't see each other's simulations.
+    pub fn get(&self, client: &Client, id: Uuid) -> Result<Arc<StoredSimulation>, Rejection> {
+        let inner = self.inner.lock().unwrap();
+        match inner.simulations.get(&id) {
+            Some(entry) if entry.owner == client.label() => Ok(entry.simulation.clone()),
+            _ => Err(warp::reject::custom(SimulationNotFoundError)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Address, Bytes};
+    use revm::interpreter::InstructionResult;
+    use uuid::Uuid;
+
+    use super::SimulationStore;
+    use crate::auth::{ApiKey, Auth, Client, Route};
+    use crate::config::{config, Config};
+    use crate::hardfork::Hardfork;
+    use crate::simulation::{SimulationRequest, SimulationResponse};
+
+    fn request() -> SimulationRequest {
+        SimulationRequest {
+            chain_id: 1,
+            from: Address::zero(),
+            to: Address::zero(),
+            data: None,
+            gas_limit: 500_000,
+            value: None,
+            access_list: None,
+            block_number: None,
+            block_timestamp: None,
+            state_overrides: None,
+            token_overrides: None,
+            mocks: None,
+            hardfork: None,
+            blob_versioned_hashes: None,
+            max_fee_per_blob_gas: None,
+            format_trace: None,
+        }
+    }
+
+    fn response() -> SimulationResponse {
+        SimulationResponse {
+            simulation_id: Uuid::new_v4(),
+            gas_used: 21_000,
+            block_number: 1,
+            success: true,
+            trace: vec![],
+            formatted_trace: None,
+            logs: vec![],
+            exit_reason: InstructionResult::Stop,
+            return_data: Bytes::default(),
+            hardfork: Hardfork::Shanghai,
+        }
+    }
+
+    fn clients() -> (Client, Client) {
+        let key = |label: &str| ApiKey {
+            key: format!("{label}-key"),
+            label: label.to_string(),
+            chains: None,
+            routes: None,
+            rate_limit: None,
+            max_sessions: None,
+        };
+        let auth = Auth::new(&Config {
+            api_key: None,
+            api_keys: vec![key("a"), key("b")],
+            ..config()
+        });
+
+        (
+            auth.authorize(Some("a-key".to_string()), Route::Simulations)
+                .unwrap(),
+            auth.authorize(Some("b-key".to_string()), Route::Simulations)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_store_owner() {
+        let store = SimulationStore::new(10);
+        let (a, b) = clients();
+
+        let response = response();
+        let id = response.simulation_id;
+        store.insert(&a, request(), response.clone(), None);
+
+        assert_eq!(store.get(&a, id).unwrap().response, response);
+        assert!(store.get(&b, id).is_err());
+        assert!(store.get(&a, Uuid::new_v4()).is_err());
+    }
+
+    #[test]
+    fn test_store_evicts_oldest() {
+        let store = SimulationStore::new(2);
+        let (a, _) = clients();
+
+        let ids: Vec<Uuid> = (0..3)
+            .map(|_| {
+                let response = response();
+                let id = response.simulation_id;
+                store.insert(&a, request(), response, None);
+                id
+            })
+            .collect();
+
+        assert!(store.get(&a, ids[0]).is_err());
+        assert!(store.get(&a, ids[1]).is_ok());
+        assert!(store.get(&a, ids[2]).is_ok());
+    }
+
+    #[test]
+    fn test_store_disabled() {
+        let store = SimulationStore::new(0);
+        let (a, _) = clients();
+
+        let response = response();
+        let id = response.simulation_id;
+        store.insert(&a, request(), response, None);
+
+        assert!(store.get(&a, id).is_err());
+    }
+}
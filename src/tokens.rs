@@ -0,0 +1,410 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use ethers::abi::{self, Address, Hash, Token, Uint};
+use ethers::types::{Bytes, H256, U512};
+use ethers::utils::keccak256;
+use serde::{Deserialize, Serialize};
+use warp::Rejection;
+
+use crate::errors::{OverrideError, TokenSlotNotFoundError};
+use crate::evm::{Evm, StorageOverride};
+use crate::simulation::PermissiveUint;
+
+/// An override of an ERC-20 balance or allowance, for tokens whose storage layout isn't known.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum TokenOverride {
+    Balance {
+        token: Address,
+        holder: Address,
+        balance: PermissiveUint,
+    },
+    Allowance {
+        token: Address,
+        owner: Address,
+        spender: Address,
+        amount: PermissiveUint,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Mapping {
+    Balance,
+    Allowance,
+}
+
+impl Mapping {
+    /// The call reading the mapping at `keys`, `balanceOf` or `allowance`.
+    fn call(self, keys: &[Address]) -> Bytes {
+        let selector: [u8; 4] = match self {
+            Mapping::Balance => [0x70, 0xa0, 0x82, 0x31],
+            Mapping::Allowance => [0xdd, 0x62, 0xed, 0x3e],
+        };
+        let keys: Vec<Token> = keys.iter().copied().map(Token::Address).collect();
+        [selector.as_slice(), &abi::encode(&keys)].concat().into()
+    }
+}
+
+/// Where a token keeps one of its mappings, found by probing it once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Layout {
+    /// The slot of the mapping itself.
+    base: Uint,
+    /// Vyper hashes the slot before the key, unlike Solidity.
+    vyper: bool,
+    /// Whether the token stores shares, rather than the amounts it returns.
+    rebasing: bool,
+}
+
+/// Mappings are only looked for in the first slots of a contract.
+const MAX_BASE_SLOT: u64 = 256;
+
+/// Written to the slots a token reads to find which holds the value it returns. It fits in 96
+/// bits, as some tokens only read that much of a slot.
+const SENTINEL: u128 = 0x5eed_5eed_5eed_5eed_5eed;
+
+/// A chain, a block of it, a token and one of its mappings.
+type LayoutKey = (u64, u64, Address, Mapping);
+
+/// The layouts discovered so far, by chain, block, token and mapping, as a token may be upgraded
+/// to another layout. Once full, the oldest ones are dropped first.
+pub struct TokenLayouts {
+    capacity: usize,
+    inner: Mutex<LayoutsInner>,
+}
+
+#[derive(Default)]
+struct LayoutsInner {
+    layouts: HashMap<LayoutKey, Layout>,
+    order: VecDeque<LayoutKey>,
+}
+
+impl TokenLayouts {
+    pub fn new(capacity: usize) -> Self {
+        TokenLayouts {
+            capacity,
+            inner: Mutex::new(LayoutsInner::default()),
+        }
+    }
+
+    fn get(&self, key: &LayoutKey) -> Option<Layout> {
+        self.inner.lock().unwrap().layouts.get(key).copied()
+    }
+
+    fn insert(&self, key: LayoutKey, layout: Layout) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.layouts.insert(key, layout).is_some() {
+            return;
+        }
+        inner.order.push_back(key);
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.layouts.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl Layout {
+    /// The slot holding the value of the mapping at `keys`.
+    fn slot(&self, keys: &[Address]) -> Hash {
+        let mut base = Hash::zero();
+        self.base.to_big_endian(base.as_bytes_mut());
+        keys.iter().fold(base, |slot, key| {
+            let key = Hash::from(*key);
+            let preimage = if self.vyper {
+                [slot.as_bytes(), key.as_bytes()].concat()
+            } else {
+                [key.as_bytes(), slot.as_bytes()].concat()
+            };
+            H256(keccak256(preimage))
+        })
+    }
+
+    /// The layout of a mapping holding the value at `keys` in `slot`, if it's a simple one.
+    fn derive(slot: Hash, keys: &[Address], rebasing: bool) -> Option<Layout> {
+        (0..MAX_BASE_SLOT)
+            .flat_map(|base| {
+                [false, true].map(|vyper| Layout {
+                    base: base.into(),
+                    vyper,
+                    rebasing,
+                })
+            })
+            .find(|layout| layout.slot(keys) == slot)
+    }
+}
+
+/// Sets the balances and allowances of `overrides`, finding where each token stores them.
+pub fn override_tokens(
+    evm: &mut Evm,
+    layouts: &TokenLayouts,
+    chain_id: u64,
+    overrides: &[TokenOverride],
+) -> Result<(), Rejection> {
+    for token_override in overrides {
+        let (token, mapping, keys, amount) = match token_override {
+            TokenOverride::Balance {
+                token,
+                holder,
+                balance,
+            } => (
+                *token,
+                Mapping::Balance,
+                vec![*holder],
+                Uint::from(*balance),
+            ),
+            TokenOverride::Allowance {
+                token,
+                owner,
+                spender,
+                amount,
+            } => (
+                *token,
+                Mapping::Allowance,
+                vec![*owner, *spender],
+                Uint::from(*amount),
+            ),
+        };
+
+        let (slot, rebasing) = find_slot(evm, layouts, chain_id, token, mapping, &keys)?;
+        let value = if rebasing {
+            // Shares are worth the same whatever the holder, so the value of some gives how many
+            // are needed, rounding up.
+            let worth = read_with(evm, token, &mapping.call(&keys), slot, SENTINEL.into())?
+                .filter(|worth| !worth.is_zero())
+                .ok_or_else(|| warp::reject::custom(TokenSlotNotFoundError))?;
+            let worth = U512::from(worth);
+            let shares = (amount.full_mul(SENTINEL.into()) + worth - U512::one()) / worth;
+            Uint::try_from(shares).map_err(|_| warp::reject::custom(OverrideError))?
+        } else {
+            amount
+        };
+        set(evm, token, slot, value)?;
+    }
+
+    Ok(())
+}
+
+/// The slot holding the value of `mapping` at `keys`, and whether it holds shares.
+fn find_slot(
+    evm: &mut Evm,
+    layouts: &TokenLayouts,
+    chain_id: u64,
+    token: Address,
+    mapping: Mapping,
+    keys: &[Address],
+) -> Result<(Hash, bool), Rejection> {
+    let key = (chain_id, evm.get_block().as_u64(), token, mapping);
+    if let Some(layout) = layouts.get(&key) {
+        return Ok((layout.slot(keys), layout.rebasing));
+    }
+
+    let data = mapping.call(keys);
+    let (returned, mut slots) = evm.probe(token, data.clone())?;
+    let current = decode(&returned).ok_or_else(|| warp::reject::custom(TokenSlotNotFoundError))?;
+    slots.sort();
+
+    // A slot holding the value itself is preferred to one holding shares.
+    let mut found = None;
+    for slot in slots {
+        match check(evm, token, &data, slot, current)? {
+            Some(false) => {
+                found = Some((slot, false));
+                break;
+            }
+            Some(true) if found.is_none() => found = Some((slot, true)),
+            _ => {}
+        }
+    }
+
+    let (slot, rebasing) = found.ok_or_else(|| warp::reject::custom(TokenSlotNotFoundError))?;
+    match Layout::derive(slot, keys, rebasing) {
+        Some(layout) => {
+            layouts.insert(key, layout);
+        }
+        None => {
+            tracing::debug!(target: "ts::api", ?token, ?slot, "token layout not cached");
+        }
+    }
+
+    Ok((slot, rebasing))
+}
+
+/// Whether `slot` holds the value returned by `data`, `Some(false)`, shares of it,
+/// `Some(true)`, or neither. The slot is left as it was.
+fn check(
+    evm: &mut Evm,
+    token: Address,
+    data: &Bytes,
+    slot: Hash,
+    current: Uint,
+) -> Result<Option<bool>, Rejection> {
+    let original = evm.get_storage(token, slot)?;
+    let sentinel = Uint::from(SENTINEL);
+
+    let mut classify = || -> Result<Option<bool>, Rejection> {
+        Ok(match read_with(evm, token, data, slot, sentinel)? {
+            Some(value) if value == sentinel => Some(false),
+            Some(value) if value != current && !value.is_zero() => {
+                // The value must be proportional to the shares, unlike the totals it depends on.
+                let none = read_with(evm, token, data, slot, Uint::zero())?;
+                let double = read_with(evm, token, data, slot, sentinel * 2u64)?;
+                let proportional = double.map_or(false, |double| {
+                    let expected = value * 2u64;
+                    double.max(expected) - double.min(expected) <= Uint::one()
+                });
+                (none == Some(Uint::zero()) && proportional).then_some(true)
+            }
+            _ => None,
+        })
+    };
+    let result = classify();
+
+    // Restored even if probing failed, so that no sentinel is left in the storage of the token.
+    set(evm, token, slot, original)?;
+    result
+}
+
+/// What `data` returns once `slot` is set to `value`.
+fn read_with(
+    evm: &mut Evm,
+    token: Address,
+    data: &Bytes,
+    slot: Hash,
+    value: Uint,
+) -> Result<Option<Uint>, Rejection> {
+    set(evm, token, slot, value)?;
+    let (returned, _) = evm.probe(token, data.clone())?;
+    Ok(decode(&returned))
+}
+
+fn set(evm: &mut Evm, token: Address, slot: Hash, value: Uint) -> Result<(), Rejection> {
+    evm.override_account(
+        token,
+        None,
+        None,
+        None,
+        Some(StorageOverride {
+            slots: HashMap::from([(slot, value)]),
+            diff: true,
+        }),
+    )?;
+    Ok(())
+}
+
+fn decode(returned: &Bytes) -> Option<Uint> {
+    (returned.len() >= 32).then(|| Uint::from_big_endian(&returned[..32]))
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::{Address, Hash};
+    use ethers::types::H256;
+    use ethers::utils::keccak256;
+
+    use super::{Layout, Mapping, TokenLayouts, TokenOverride};
+
+    #[test]
+    fn test_layout_slot() {
+        let holder = Address::repeat_byte(0x11);
+        let spender = Address::repeat_byte(0x22);
+        let slot = |base: u8| Hash::from_low_u64_be(base as u64);
+
+        let solidity = Layout {
+            base: 3.into(),
+            vyper: false,
+            rebasing: false,
+        };
+        let balance = H256(keccak256(
+            [Hash::from(holder).as_bytes(), slot(3).as_bytes()].concat(),
+        ));
+        assert_eq!(solidity.slot(&[holder]), balance);
+
+        let vyper = Layout {
+            vyper: true,
+            ..solidity
+        };
+        let allowance = H256(keccak256(
+            [slot(3).as_bytes(), Hash::from(holder).as_bytes()].concat(),
+        ));
+        let allowance = H256(keccak256(
+            [allowance.as_bytes(), Hash::from(spender).as_bytes()].concat(),
+        ));
+        assert_eq!(vyper.slot(&[holder, spender]), allowance);
+    }
+
+    #[test]
+    fn test_layout_derive() {
+        let keys = [Address::repeat_byte(0x11), Address::repeat_byte(0x22)];
+        let layout = Layout {
+            base: 9.into(),
+            vyper: true,
+            rebasing: false,
+        };
+
+        assert_eq!(
+            Layout::derive(layout.slot(&keys), &keys, false),
+            Some(layout)
+        );
+        assert_eq!(Layout::derive(Hash::repeat_byte(0x33), &keys, false), None);
+    }
+
+    #[test]
+    fn test_token_layouts_evict_oldest() {
+        let layouts = TokenLayouts::new(2);
+        let layout = Layout {
+            base: 1.into(),
+            vyper: false,
+            rebasing: false,
+        };
+        let key = |block: u64| (1, block, Address::repeat_byte(0x11), Mapping::Balance);
+
+        layouts.insert(key(1), layout);
+        layouts.insert(key(2), layout);
+        layouts.insert(key(3), layout);
+
+        assert_eq!(layouts.get(&key(1)), None);
+        assert_eq!(layouts.get(&key(2)), Some(layout));
+        assert_eq!(layouts.get(&key(3)), Some(layout));
+
+        let disabled = TokenLayouts::new(0);
+        disabled.insert(key(1), layout);
+        assert_eq!(disabled.get(&key(1)), None);
+    }
+
+    #[test]
+    fn test_mapping_call() {
+        let holder = Address::repeat_byte(0x11);
+        let call = Mapping::Balance.call(&[holder]);
+
+        assert_eq!(call.len(), 36);
+        assert_eq!(call[..4], [0x70, 0xa0, 0x82, 0x31]);
+        assert_eq!(call[16..], holder.0);
+    }
+
+    #[test]
+    fn test_token_override_deserialize() {
+        let balance: TokenOverride = serde_json::from_value(serde_json::json!({
+            "token": Address::repeat_byte(0x11),
+            "holder": Address::repeat_byte(0x22),
+            "balance": "1000",
+        }))
+        .unwrap();
+        assert!(matches!(balance, TokenOverride::Balance { .. }));
+
+        let allowance: TokenOverride = serde_json::from_value(serde_json::json!({
+            "token": Address::repeat_byte(0x11),
+            "owner": Address::repeat_byte(0x22),
+            "spender": Address::repeat_byte(0x33),
+            "amount": "0x10",
+        }))
+        .unwrap();
+        assert!(matches!(allowance, TokenOverride::Allowance { .. }));
+    }
+}
@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ethers::abi::{self, Address, Hash, ParamType, Token, Uint};
+use ethers::core::types::Log;
+use ethers::types::{Bytes, H256};
+use ethers::utils::keccak256;
+use serde::{Deserialize, Serialize};
+use warp::reply::Json;
+use warp::Rejection;
+
+use crate::auth::Client;
+use crate::config::Config;
+use crate::errors::InvalidEntryPointError;
+use crate::hardfork::Hardfork;
+use crate::mocks::Mock;
+use crate::simulation::{
+    execute, fork_for, respond, PermissiveUint, SimulationRequest, SimulationResponse,
+    StateOverride,
+};
+use crate::tokens::TokenOverride;
+use crate::SharedSimulationState;
+
+/// The gas limit of `simulateValidation` and `handleOps` unless given, enough for most operations.
+const DEFAULT_GAS_LIMIT: u64 = 10_000_000;
+
+/// An ERC-4337 user operation, as accepted by the EntryPoint v0.6.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperation {
+    pub sender: Address,
+    pub nonce: PermissiveUint,
+    #[serde(default)]
+    pub init_code: Bytes,
+    #[serde(default)]
+    pub call_data: Bytes,
+    pub call_gas_limit: PermissiveUint,
+    pub verification_gas_limit: PermissiveUint,
+    pub pre_verification_gas: PermissiveUint,
+    pub max_fee_per_gas: PermissiveUint,
+    pub max_priority_fee_per_gas: PermissiveUint,
+    #[serde(default)]
+    pub paymaster_and_data: Bytes,
+    #[serde(default)]
+    pub signature: Bytes,
+}
+
+impl UserOperation {
+    fn token(&self) -> Token {
+        Token::Tuple(vec![
+            Token::Address(self.sender),
+            Token::Uint(self.nonce.into()),
+            Token::Bytes(self.init_code.to_vec()),
+            Token::Bytes(self.call_data.to_vec()),
+            Token::Uint(self.call_gas_limit.into()),
+            Token::Uint(self.verification_gas_limit.into()),
+            Token::Uint(self.pre_verification_gas.into()),
+            Token::Uint(self.max_fee_per_gas.into()),
+            Token::Uint(self.max_priority_fee_per_gas.into()),
+            Token::Bytes(self.paymaster_and_data.to_vec()),
+            Token::Bytes(self.signature.to_vec()),
+        ])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationRequest {
+    pub chain_id: u64,
+    pub entry_point: Address,
+    pub user_operation: UserOperation,
+    /// Sends `handleOps` and receives its fees.
+    pub bundler: Option<Address>,
+    pub gas_limit: Option<u64>,
+    pub block_number: Option<u64>,
+    pub block_timestamp: Option<u64>,
+    pub state_overrides: Option<HashMap<Address, StateOverride>>,
+    pub token_overrides: Option<Vec<TokenOverride>>,
+    pub mocks: Option<Vec<Mock>>,
+    pub hardfork: Option<Hardfork>,
+    pub format_trace: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationResponse {
+    /// What `simulateValidation` returned, if the operation is valid.
+    pub validation: Option<ValidationResult>,
+    /// Why the EntryPoint rejected the operation, like `AA21 didn't pay prefund`.
+    pub failure: Option<String>,
+    /// What running the operation with `handleOps` did, if it was accepted.
+    pub execution: Option<ExecutionResult>,
+    /// The simulation of `handleOps`, if the operation passed validation.
+    pub simulation: Option<SimulationResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationResult {
+    /// The gas used by the validation, including the pre-verification gas.
+    pub pre_op_gas: Uint,
+    pub prefund: Uint,
+    pub sig_failed: bool,
+    pub valid_after: u64,
+    pub valid_until: u64,
+    pub paymaster_context: Bytes,
+    pub aggregator: Option<Address>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionResult {
+    pub user_op_hash: Hash,
+    /// Whether the call of the operation succeeded, `handleOps` itself succeeding anyway.
+    pub success: bool,
+    pub actual_gas_cost: Uint,
+    pub actual_gas_used: Uint,
+    pub revert_reason: Option<Bytes>,
+    pub gas: GasBreakdown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GasBreakdown {
+    pub pre_verification: Uint,
+    pub verification: Uint,
+    /// The gas of the call and of the `postOp` of the paymaster.
+    pub call: Uint,
+}
+
+/// Validates `request.user_operation` with `simulateValidation` of its EntryPoint, then runs it
+/// with `handleOps` if it's valid.
+pub async fn simulate_user_operation(
+    client: Client,
+    request: UserOperationRequest,
+    config: Config,
+    state: Arc<SharedSimulationState>,
+) -> Result<Json, Rejection> {
+    let operation = request.user_operation.clone();
+    let bundler = request
+        .bundler
+        .unwrap_or_else(|| Address::from_low_u64_be(0x4337));
+    let validation = SimulationRequest {
+        chain_id: request.chain_id,
+        from: Address::zero(),
+        to: request.entry_point,
+        data: Some(call([0xee, 0x21, 0x94, 0x23], vec![operation.token()])),
+        gas_limit: request.gas_limit.unwrap_or(DEFAULT_GAS_LIMIT),
+        value: None,
+        access_list: None,
+        block_number: request.block_number,
+        block_timestamp: request.block_timestamp,
+        state_overrides: request.state_overrides,
+        token_overrides: request.token_overrides,
+        mocks: request.mocks,
+        hardfork: request.hardfork,
+        blob_versioned_hashes: None,
+        max_fee_per_blob_gas: None,
+        format_trace: None,
+    };
+
+    let mut evm = fork_for(&client, &validation, &config).await?;
+
+    // The overrides stay in place for `handleOps`, calls which aren't committed keeping them.
+    let execution = SimulationRequest {
+        from: bundler,
+        data: Some(call(
+            [0x1f, 0xad, 0x94, 0x8c],
+            vec![
+                Token::Array(vec![operation.token()]),
+                Token::Address(bundler),
+            ],
+        )),
+        state_overrides: None,
+        token_overrides: None,
+        mocks: None,
+        format_trace: request.format_trace,
+        ..validation.clone()
+    };
+
+    let result = execute(&mut evm, &state.token_layouts, validation, false).await?;
+    let validation = match decode_validation(&result.return_data) {
+        Some(Ok(validation)) => validation,
+        Some(Err(failure)) => {
+            return Ok(warp::reply::json(&UserOperationResponse {
+                validation: None,
+                failure: Some(failure),
+                execution: None,
+                simulation: None,
+            }))
+        }
+        None => return Err(warp::reject::custom(InvalidEntryPointError)),
+    };
+
+    let result = execute(&mut evm, &state.token_layouts, execution, false).await?;
+    let (simulation, _) = respond(result);
+    let (failure, execution) = if simulation.success {
+        let execution = decode_execution(
+            request.entry_point,
+            &simulation.logs,
+            Uint::from(operation.pre_verification_gas),
+            validation.pre_op_gas,
+        );
+        (None, execution)
+    } else {
+        (decode_failed_op(&simulation.return_data), None)
+    };
+
+    Ok(warp::reply::json(&UserOperationResponse {
+        validation: Some(validation),
+        failure,
+        execution,
+        simulation: Some(simulation),
+    }))
+}
+
+fn call(selector: [u8; 4], tokens: Vec<Token>) -> Bytes {
+    [selector.as_slice(), &abi::encode(&tokens)].concat().into()
+}
+
+/// The result of `simulateValidation`, which always reverts, from what it reverted with: the
+/// validation result, the reason the operation failed, or nothing if it isn't an EntryPoint.
+fn decode_validation(returned: &Bytes) -> Option<Result<ValidationResult, String>> {
+    let stake = || ParamType::Tuple(vec![ParamType::Uint(256), ParamType::Uint(256)]);
+    let mut types = vec![
+        ParamType::Tuple(vec![
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+            ParamType::Bool,
+            ParamType::Uint(48),
+            ParamType::Uint(48),
+            ParamType::Bytes,
+        ]),
+        stake(),
+        stake(),
+        stake(),
+    ];
+
+    let aggregated = match returned.get(..4)? {
+        // ValidationResult
+        [0xe0, 0xcf, 0xf0, 0x5f] => false,
+        // ValidationResultWithAggregation
+        [0xfa, 0xec, 0xb4, 0xe4] => true,
+        _ => return decode_failed_op(returned).map(Err),
+    };
+    if aggregated {
+        types.push(ParamType::Tuple(vec![ParamType::Address, stake()]));
+    }
+
+    let mut tokens = abi::decode(&types, &returned[4..]).ok()?.into_iter();
+    let Some(Token::Tuple(info)) = tokens.next() else {
+        return None;
+    };
+    let aggregator = tokens.nth(3).and_then(|aggregator| match aggregator {
+        Token::Tuple(aggregator) => aggregator.first()?.clone().into_address(),
+        _ => None,
+    });
+
+    match info.as_slice() {
+        [Token::Uint(pre_op_gas), Token::Uint(prefund), Token::Bool(sig_failed), Token::Uint(valid_after), Token::Uint(valid_until), Token::Bytes(paymaster_context)] => {
+            Some(Ok(ValidationResult {
+                pre_op_gas: *pre_op_gas,
+                prefund: *prefund,
+                sig_failed: *sig_failed,
+                valid_after: valid_after.low_u64(),
+                valid_until: valid_until.low_u64(),
+                paymaster_context: paymaster_context.clone().into(),
+                aggregator,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// The reason of `FailedOp(uint256 opIndex, string reason)`.
+fn decode_failed_op(returned: &Bytes) -> Option<String> {
+    if returned.get(..4)? != [0x22, 0x02, 0x66, 0xb6] {
+        return None;
+    }
+    match abi::decode(&[ParamType::Uint(256), ParamType::String], &returned[4..])
+        .ok()?
+        .pop()?
+    {
+        Token::String(reason) => Some(reason),
+        _ => None,
+    }
+}
+
+/// The result of the operation from the events `handleOps` emitted.
+fn decode_execution(
+    entry_point: Address,
+    logs: &[Log],
+    pre_verification_gas: Uint,
+    pre_op_gas: Uint,
+) -> Option<ExecutionResult> {
+    let event = |signature: &str| H256(keccak256(signature));
+    let emitted = |topic: H256| {
+        logs.iter()
+            .find(|log| log.address == entry_point && log.topics.first() == Some(&topic))
+    };
+
+    let log = emitted(event(
+        "UserOperationEvent(bytes32,address,address,uint256,bool,uint256,uint256)",
+    ))?;
+    let user_op_hash = *log.topics.get(1)?;
+    let tokens = abi::decode(
+        &[
+            ParamType::Uint(256),
+            ParamType::Bool,
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+        ],
+        &log.data,
+    )
+    .ok()?;
+    let [_, Token::Bool(success), Token::Uint(actual_gas_cost), Token::Uint(actual_gas_used)] =
+        tokens.as_slice()
+    else {
+        return None;
+    };
+
+    let revert_reason = emitted(event(
+        "UserOperationRevertReason(bytes32,address,uint256,bytes)",
+    ))
+    .and_then(|log| {
+        abi::decode(&[ParamType::Uint(256), ParamType::Bytes], &log.data)
+            .ok()?
+            .pop()?
+            .into_bytes()
+    })
+    .map(Bytes::from);
+
+    Some(ExecutionResult {
+        user_op_hash,
+        success: *success,
+        actual_gas_cost: *actual_gas_cost,
+        actual_gas_used: *actual_gas_used,
+        revert_reason,
+        gas: GasBreakdown {
+            pre_verification: pre_verification_gas,
+            verification: pre_op_gas.saturating_sub(pre_verification_gas),
+            call: actual_gas_used.saturating_sub(pre_op_gas),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::{self, Address, Token, Uint};
+    use ethers::core::types::Log;
+    use ethers::types::{Bytes, H256};
+    use ethers::utils::keccak256;
+
+    use super::{decode_execution, decode_failed_op, decode_validation, UserOperation};
+
+    fn revert(selector: [u8; 4], tokens: &[Token]) -> Bytes {
+        [selector.as_slice(), &abi::encode(tokens)].concat().into()
+    }
+
+    #[test]
+    fn test_user_operation_token() {
+        let operation: UserOperation = serde_json::from_value(serde_json::json!({
+            "sender": Address::repeat_byte(0x11),
+            "nonce": "0x1",
+            "callData": "0x12345678",
+            "callGasLimit": "100000",
+            "verificationGasLimit": "0x186a0",
+            "preVerificationGas": "50000",
+            "maxFeePerGas": "1",
+            "maxPriorityFeePerGas": "1",
+        }))
+        .unwrap();
+
+        let Token::Tuple(fields) = operation.token() else {
+            panic!("not a tuple");
+        };
+        assert_eq!(fields.len(), 11);
+        assert_eq!(fields[3], Token::Bytes(vec![0x12, 0x34, 0x56, 0x78]));
+        assert_eq!(fields[5], Token::Uint(100_000.into()));
+        assert_eq!(fields[10], Token::Bytes(vec![]));
+    }
+
+    #[test]
+    fn test_decode_validation() {
+        let stake = || Token::Tuple(vec![Token::Uint(0.into()), Token::Uint(0.into())]);
+        let returned = revert(
+            [0xe0, 0xcf, 0xf0, 0x5f],
+            &[
+                Token::Tuple(vec![
+                    Token::Uint(80_000.into()),
+                    Token::Uint(1_000.into()),
+                    Token::Bool(false),
+                    Token::Uint(0.into()),
+                    Token::Uint(u64::from(u32::MAX).into()),
+                    Token::Bytes(vec![0xab]),
+                ]),
+                stake(),
+                stake(),
+                stake(),
+            ],
+        );
+
+        let validation = decode_validation(&returned).unwrap().unwrap();
+        assert_eq!(validation.pre_op_gas, 80_000.into());
+        assert_eq!(validation.prefund, 1_000.into());
+        assert!(!validation.sig_failed);
+        assert_eq!(validation.valid_until, u64::from(u32::MAX));
+        assert_eq!(validation.paymaster_context, Bytes::from(vec![0xab]));
+        assert_eq!(validation.aggregator, None);
+
+        let failed = revert(
+            [0x22, 0x02, 0x66, 0xb6],
+            &[
+                Token::Uint(0.into()),
+                Token::String("AA21 didn't pay prefund".to_string()),
+            ],
+        );
+        assert_eq!(
+            decode_validation(&failed),
+            Some(Err("AA21 didn't pay prefund".to_string()))
+        );
+        assert_eq!(
+            decode_failed_op(&failed),
+            Some("AA21 didn't pay prefund".to_string())
+        );
+
+        assert_eq!(decode_validation(&Bytes::default()), None);
+    }
+
+    #[test]
+    fn test_decode_execution() {
+        let entry_point = Address::repeat_byte(0x11);
+        let hash = H256::repeat_byte(0x22);
+        let log = Log {
+            address: entry_point,
+            topics: vec![
+                H256(keccak256(
+                    "UserOperationEvent(bytes32,address,address,uint256,bool,uint256,uint256)",
+                )),
+                hash,
+            ],
+            data: abi::encode(&[
+                Token::Uint(0.into()),
+                Token::Bool(true),
+                Token::Uint(1_000.into()),
+                Token::Uint(150_000.into()),
+            ])
+            .into(),
+            ..Default::default()
+        };
+
+        let execution =
+            decode_execution(entry_point, &[log], Uint::from(50_000), Uint::from(80_000)).unwrap();
+
+        assert_eq!(execution.user_op_hash, hash);
+        assert!(execution.success);
+        assert_eq!(execution.revert_reason, None);
+        assert_eq!(execution.gas.verification, 30_000.into());
+        assert_eq!(execution.gas.call, 70_000.into());
+
+        assert!(decode_execution(Address::zero(), &[], Uint::zero(), Uint::zero()).is_none());
+    }
+}
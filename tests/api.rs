@@ -1,25 +1,36 @@
 use std::{fs::File, sync::Arc};
 
-use dashmap::DashMap;
 use enso_temper::{
+    call_bundle::{CallBundleResponse, JsonRpcResponse},
     config::{config, Config},
+    cors,
     errors::{handle_rejection, ErrorMessage},
-    simulate_routes,
+    hardfork::Hardfork,
+    health,
+    health::{HealthResponse, ReadinessResponse},
+    metrics, ready,
+    replay::{Divergence, DivergenceField, InsertResponse, ReplayBlockResponse},
+    request_id::with_request_id,
+    sessions, simulate_routes,
     simulation::{
-        SimulationRequest, SimulationResponse, StatefulSimulationEndResponse,
-        StatefulSimulationResponse,
+        AccountState, RerunResponse, SimulationRequest, SimulationResponse,
+        StatefulSimulationEndResponse, StatefulSimulationResponse,
     },
+    store::StoredSimulation,
+    user_operation::UserOperationResponse,
     SharedSimulationState,
 };
-use ethers::types::U256;
+use ethers::abi::Token;
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Address, Bytes, TransactionRequest, H256, U256};
+use ethers::utils::keccak256;
 use warp::Filter;
 
 fn filter(
     config: Config,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = std::convert::Infallible> + Clone {
-    let shared_state: Arc<SharedSimulationState> = Arc::new(SharedSimulationState {
-        evms: Arc::new(DashMap::new()),
-    });
+    let shared_state = Arc::new(SharedSimulationState::new(&config));
 
     warp::any()
         .and(simulate_routes(config, shared_state))
@@ -57,6 +68,11 @@ async fn post_simulate_file() {
     let file = File::open("tests/expected.json").expect("file should open read only");
     let expected: SimulationResponse =
         serde_json::from_reader(file).expect("file should be proper JSON");
+    // Simulation ids are random.
+    let expected = SimulationResponse {
+        simulation_id: body.simulation_id,
+        ..expected
+    };
 
     assert_eq!(body, expected);
 }
@@ -95,6 +111,11 @@ async fn post_simulate_file_etherscan() {
     let file = File::open("tests/expected_etherscan.json").expect("file should open read only");
     let expected: SimulationResponse =
         serde_json::from_reader(file).expect("file should be proper JSON");
+    // Simulation ids are random.
+    let expected = SimulationResponse {
+        simulation_id: body.simulation_id,
+        ..expected
+    };
 
     assert_eq!(body, expected);
 }
@@ -427,6 +448,128 @@ async fn post_simulate_not_enough_gas() {
     assert_eq!(body.message, "OUT_OF_GAS".to_string());
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_fork_timeout() {
+    // A non-routable address, so the connection hangs until the fork setup times out.
+    let config = Config {
+        fork_url: Some("http://10.255.255.1".to_string()),
+        fork_timeout: 1,
+        ..config()
+    };
+    let filter = filter(config);
+
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x66fc62c1748e45435b06cf8dd105b73e9855f93e",
+      "gasLimit": 21000,
+      "value": "100000",
+      "blockNumber": 16784600
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 504);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "FORK_TIMEOUT".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_too_many_simulations() {
+    let config = Config {
+        max_concurrent_simulations: 0,
+        max_queued_simulations: 0,
+        ..config()
+    };
+    let filter = filter(config);
+
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x66fc62c1748e45435b06cf8dd105b73e9855f93e",
+      "gasLimit": 21000,
+      "value": "100000",
+      "blockNumber": 16784600
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 429);
+    assert_eq!(res.headers()["retry-after"], "1");
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "TOO_MANY_SIMULATIONS".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_rate_limit_after_authentication() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        api_key: Some("secret".to_string()),
+        rate_limit: Some(1),
+        ..config()
+    });
+
+    let json = serde_json::json!({
+        "chainId": 31337,
+        "from": STUB_SENDER,
+        "to": STUB_RECIPIENT,
+        "gasLimit": 100000,
+    });
+
+    // Neither unauthenticated requests, unknown paths nor lookups use up the rate limit.
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 401);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/unknown")
+        .header("X-API-KEY", "secret")
+        .json(&json)
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 404);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulations/{}", uuid::Uuid::new_v4()))
+        .header("X-API-KEY", "secret")
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 404);
+
+    let simulate = || {
+        warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .header("X-API-KEY", "secret")
+            .json(&json)
+            .reply(&filter)
+    };
+
+    assert_eq!(simulate().await.status(), 200);
+    assert_eq!(simulate().await.status(), 429);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn post_simulate_invalid_from() {
     let filter = filter(config());
@@ -757,6 +900,94 @@ async fn post_simulate_stateful() {
     assert_eq!(res.status(), 404);
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_restore() {
+    let config = config();
+    let state = Arc::new(SharedSimulationState::new(&config));
+    let filter = warp::any()
+        .and(simulate_routes(config.clone(), state.clone()))
+        .recover(handle_rejection);
+
+    let new_simulation_req = serde_json::json!({
+        "chainId": 1,
+        "gasLimit": 5000000,
+        "blockNumber": 16968594,
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&new_simulation_req)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let simulation_response_body: StatefulSimulationResponse =
+        serde_json::from_slice(res.body()).unwrap();
+    let id = simulation_response_body.stateful_simulation_id;
+
+    // Approve 10 USDT.
+    let approve_request = serde_json::json!([{
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0x095ea7b300000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b0000000000000000000000000000000000000000000000000000000000989680",
+      "gasLimit": 5000000,
+      "blockNumber": 16968595,
+    }]);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}").as_str())
+        .json(&approve_request)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let path = std::env::temp_dir().join(format!("temper-sessions-{id}.json"));
+    assert_eq!(sessions::save(&path, &state).unwrap(), 1);
+
+    let restored_state = Arc::new(SharedSimulationState::new(&config));
+    assert_eq!(
+        sessions::restore(&path, &config, &restored_state)
+            .await
+            .unwrap(),
+        1
+    );
+    let filter = warp::any()
+        .and(simulate_routes(config, restored_state))
+        .recover(handle_rejection);
+
+    // The allowance set before saving is still there.
+    let allowance_request = serde_json::json!([{
+      "chainId": 1,
+      "from": "0x93621dca56fe26cdee86e4f6b18e116e9758ff11",
+      "to": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+      "data": "0xdd62ed3e00000000000000000000000093621dca56fe26cdee86e4f6b18e116e9758ff1100000000000000000000000060f727bdead2ce49b00f2a2133fc707b931d130b",
+      "gasLimit": 5000000,
+      "blockNumber": 16968595,
+    }]);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(format!("/simulate-stateful/{id}").as_str())
+        .json(&allowance_request)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body[0].success);
+    assert_eq!(
+        U256::from_big_endian(&body[0].return_data),
+        U256::from(10000000)
+    );
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn post_simulate_time_sensitive_tx() {
     let config = Config {
@@ -809,3 +1040,1873 @@ async fn post_simulate_time_sensitive_tx() {
     // when we use the timestamp of the next block.
     assert!(!body.success);
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_simulation() {
+    let filter = filter(config());
+
+    let file = File::open("tests/body.json").expect("file should open read only");
+    let json: SimulationRequest =
+        serde_json::from_reader(file).expect("file should be proper JSON");
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulations/{}", body.simulation_id))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let stored: StoredSimulation = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(stored.response, body);
+    assert_eq!(stored.request.to, json.to);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulations/{}", uuid::Uuid::new_v4()))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "SIMULATION_NOT_FOUND".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulation_rerun() {
+    let filter = filter(config());
+
+    let file = File::open("tests/body.json").expect("file should open read only");
+    let json: SimulationRequest =
+        serde_json::from_reader(file).expect("file should be proper JSON");
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let original: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    // Rerunning on the same block gives the same results.
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulations/{}/rerun", original.simulation_id))
+        .json(&serde_json::json!({ "blockNumber": json.block_number }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: RerunResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.original_simulation_id, original.simulation_id);
+    assert_ne!(body.simulation.simulation_id, original.simulation_id);
+    assert!(!body.comparison.success.changed);
+    assert!(!body.comparison.gas_used.changed);
+    assert!(!body.comparison.logs.changed);
+    assert!(!body.comparison.state_diff.unwrap().changed);
+
+    // The rerun is itself stored.
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulations/{}", body.simulation.simulation_id))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    // Rerunning on the latest block.
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulations/{}/rerun", original.simulation_id))
+        .json(&serde_json::json!({}))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: RerunResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.comparison.block_number.changed);
+    assert_eq!(body.comparison.block_number.original, original.block_number);
+}
+
+const STUB_SENDER: &str = "0x1000000000000000000000000000000000000001";
+const STUB_RECIPIENT: &str = "0x2000000000000000000000000000000000000002";
+const STUB_MINER: &str = "0x3000000000000000000000000000000000000003";
+const STUB_PRECEDING_TX: &str =
+    "0x1111111111111111111111111111111111111111111111111111111111111111";
+const STUB_TX: &str = "0x2222222222222222222222222222222222222222222222222222222222222222";
+/// A transfer of the third block with too little gas to run at all.
+const STUB_ERRORED_TX: &str = "0x4444444444444444444444444444444444444444444444444444444444444444";
+const STUB_LAST_TX: &str = "0x5555555555555555555555555555555555555555555555555555555555555555";
+/// A token whose `balanceOf` reads a Solidity mapping at slot 0, whatever the selector.
+const STUB_TOKEN: &str = "0x4000000000000000000000000000000000000004";
+const STUB_TOKEN_CODE: &str = "0x600435600052600060205260406000205460005260206000f3";
+/// A Safe owned by `STUB_SENDER` alone, whose `execTransaction` only makes calls approved by the
+/// owner sending them, `v` being 1, once its threshold is 1.
+const STUB_SAFE: &str = "0x5000000000000000000000000000000000000005";
+const STUB_SAFE_CODE: &str = concat!(
+    "0x60003560e01c8063a0e67e2b146100205780636a76120214610048576100ae565b6020600052600160",
+    "205273100000000000000000000000000000000000000160405260606000f35b600454600114156100ae",
+    "576101243560040180602001353314156100ae57806060013560f81c600114156100ae57506064356100",
+    "ae576044356004018035906020018190600037600060008260006024356004355af1156100ae57600160",
+    "005260206000f35b600080fd",
+);
+
+/// An EntryPoint whose `simulateValidation` accepts any operation, its validation using 80000 gas,
+/// and whose `handleOps` reports an operation of `STUB_SENDER` with hash 0x6666... which succeeded
+/// using 150000 gas in all.
+const STUB_ENTRY_POINT: &str = "0x6000000000000000000000000000000000000006";
+const STUB_ENTRY_POINT_CODE: &str = concat!(
+    "0x60003560e01c8063ee219423146100205780631fad948c1461006d57600080fd5b7fe0cff05f000000",
+    "0000000000000000000000000000000000000000000000000060005260e06004526201388060e4526103",
+    "e86101045263ffffffff6101645260c0610184526101c46000fd5b60016020526103e8604052620249f0",
+    "60605260007310000000000000000000000000000000000000017f666666666666666666666666666666",
+    "66666666666666666666666666666666667f49628fd1471006c1482da88028e9ce4dbb080b815c9b0344",
+    "d39e5a8e6ec1419f60806000a400",
+);
+
+/// A mined transfer from `STUB_SENDER` to `STUB_RECIPIENT`, paying 3 wei per gas.
+fn stub_transaction(hash: &str, index: u64, nonce: u64) -> serde_json::Value {
+    serde_json::json!({
+        "hash": hash,
+        "nonce": format!("{nonce:#x}"),
+        "blockHash": format!("0x{}", "ab".repeat(32)),
+        "blockNumber": "0x2",
+        "transactionIndex": format!("{index:#x}"),
+        "from": STUB_SENDER,
+        "to": STUB_RECIPIENT,
+        "value": "0x1",
+        "gasPrice": "0x3",
+        "gas": "0x5208",
+        "input": "0x",
+        "v": "0x1b",
+        "r": "0x1",
+        "s": "0x1",
+        "type": "0x0",
+    })
+}
+
+/// The transfer at `index` of block `number`. The errored transfer never ran, so the one after
+/// it has the same nonce.
+fn stub_block_transaction(number: u64, hash: &str, index: u64) -> serde_json::Value {
+    let nonce = if number == 3 { 0 } else { index };
+    let mut transaction = stub_transaction(hash, index, nonce);
+    transaction["blockNumber"] = serde_json::json!(format!("{number:#x}"));
+    if hash == STUB_ERRORED_TX {
+        transaction["gas"] = serde_json::json!("0x1");
+    }
+    transaction
+}
+
+fn stub_block(number: u64, transactions: Option<Vec<serde_json::Value>>) -> serde_json::Value {
+    serde_json::json!({
+        "hash": format!("0x{:064x}", number + 0xab),
+        "parentHash": format!("0x{:064x}", number + 0xaa),
+        "sha3Uncles": format!("0x{}", "00".repeat(32)),
+        "miner": STUB_MINER,
+        "stateRoot": format!("0x{}", "00".repeat(32)),
+        "transactionsRoot": format!("0x{}", "00".repeat(32)),
+        "receiptsRoot": format!("0x{}", "00".repeat(32)),
+        "logsBloom": format!("0x{}", "00".repeat(256)),
+        "difficulty": "0x0",
+        "number": format!("{number:#x}"),
+        "gasLimit": "0x1c9c380",
+        "gasUsed": "0xa410",
+        "timestamp": format!("{:#x}", 1_700_000_000 + number * 12),
+        "extraData": "0x",
+        "mixHash": format!("0x{}", "00".repeat(32)),
+        "nonce": "0x0000000000000000",
+        "baseFeePerGas": "0x1",
+        "totalDifficulty": "0x0",
+        "size": "0x0",
+        "uncles": [],
+        "transactions": transactions.unwrap_or_default(),
+    })
+}
+
+/// The receipt of a transfer, which failed on chain if `status` is 0.
+fn stub_receipt(hash: &str, index: u64, status: u64) -> serde_json::Value {
+    serde_json::json!({
+        "transactionHash": hash,
+        "transactionIndex": format!("{index:#x}"),
+        "blockHash": format!("0x{}", "ab".repeat(32)),
+        "blockNumber": "0x2",
+        "from": STUB_SENDER,
+        "to": STUB_RECIPIENT,
+        "cumulativeGasUsed": format!("{:#x}", 21_000 * (index + 1)),
+        "gasUsed": "0x5208",
+        "contractAddress": null,
+        "logs": [],
+        "status": format!("{status:#x}"),
+        "logsBloom": format!("0x{}", "00".repeat(256)),
+        "type": "0x0",
+        "effectiveGasPrice": "0x3",
+    })
+}
+
+/// Serves a chain with id 31337 and three blocks, the second one holding two transfers, returning
+/// its URL. The receipt of the first transfer claims it failed, although it can't. The first
+/// transfer of the third block can't run, the EVM refusing its gas limit.
+async fn stub_rpc() -> String {
+    let rpc = warp::post()
+        .and(warp::body::json())
+        .map(|request: serde_json::Value| {
+            let params = &request["params"];
+            let result = match request["method"].as_str().unwrap_or_default() {
+                "eth_chainId" => serde_json::json!("0x7a69"),
+                "eth_blockNumber" => serde_json::json!("0x2"),
+                "eth_gasPrice" => serde_json::json!("0x0"),
+                "eth_getBlockByNumber" => {
+                    let number = match params[0].as_str() {
+                        Some("0x1") => 1,
+                        Some("0x3") => 3,
+                        _ => 2,
+                    };
+                    let hashes = match number {
+                        2 => vec![STUB_PRECEDING_TX, STUB_TX],
+                        3 => vec![STUB_ERRORED_TX, STUB_LAST_TX],
+                        _ => Vec::new(),
+                    };
+                    let full = params[1].as_bool().unwrap_or_default();
+                    let transactions = (number != 1).then(|| {
+                        hashes
+                            .iter()
+                            .enumerate()
+                            .map(|(index, hash)| {
+                                if full {
+                                    stub_block_transaction(number, hash, index as u64)
+                                } else {
+                                    serde_json::json!(hash)
+                                }
+                            })
+                            .collect()
+                    });
+                    stub_block(number, transactions)
+                }
+                "eth_getTransactionByHash" => match params[0].as_str() {
+                    Some(STUB_PRECEDING_TX) => stub_transaction(STUB_PRECEDING_TX, 0, 0),
+                    Some(STUB_TX) => stub_transaction(STUB_TX, 1, 1),
+                    _ => serde_json::Value::Null,
+                },
+                "eth_getTransactionReceipt" => match params[0].as_str() {
+                    Some(STUB_PRECEDING_TX) => stub_receipt(STUB_PRECEDING_TX, 0, 0),
+                    Some(STUB_TX) => stub_receipt(STUB_TX, 1, 1),
+                    Some(STUB_ERRORED_TX) => stub_receipt(STUB_ERRORED_TX, 0, 1),
+                    Some(STUB_LAST_TX) => stub_receipt(STUB_LAST_TX, 1, 1),
+                    _ => serde_json::Value::Null,
+                },
+                "eth_getBalance" => serde_json::json!("0xde0b6b3a7640000"),
+                "eth_getTransactionCount" => serde_json::json!("0x0"),
+                "eth_getCode" => match params[0].as_str() {
+                    Some(STUB_TOKEN) => serde_json::json!(STUB_TOKEN_CODE),
+                    Some(STUB_SAFE) => serde_json::json!(STUB_SAFE_CODE),
+                    Some(STUB_ENTRY_POINT) => serde_json::json!(STUB_ENTRY_POINT_CODE),
+                    _ => serde_json::json!("0x"),
+                },
+                // Every slot of the token holds 100, so that clearing its storage shows, and
+                // every slot of the Safe 2, its threshold among them.
+                "eth_getStorageAt" => match params[0].as_str() {
+                    Some(STUB_TOKEN) => serde_json::json!(format!("0x{:064x}", 100)),
+                    Some(STUB_SAFE) => serde_json::json!(format!("0x{:064x}", 2)),
+                    _ => serde_json::json!(format!("0x{}", "00".repeat(32))),
+                },
+                _ => serde_json::Value::Null,
+            };
+            warp::reply::json(&serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": result,
+            }))
+        });
+
+    let (addr, server) = warp::serve(rpc).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    format!("http://{addr}")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/replay")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "txHash": STUB_TX,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    assert_eq!(body.block_number, 2);
+    assert_eq!(body.hardfork, Hardfork::Shanghai);
+
+    // The replay is stored like any other simulation, on top of the parent block.
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulations/{}", body.simulation_id))
+        .reply(&filter)
+        .await;
+
+    let stored: StoredSimulation = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(stored.request.block_number, Some(1));
+    // With the rules of the block it was mined in, rather than those of its parent.
+    assert_eq!(stored.request.hardfork, Some(Hardfork::Shanghai));
+    // On top of the transfer preceding it in its block.
+    let state_diff = stored.state_diff.unwrap();
+    let recipient: Address = STUB_RECIPIENT.parse().unwrap();
+    let balance = state_diff[&recipient].balance.clone().unwrap();
+    let initial = U256::exp10(18);
+
+    assert_eq!(balance.from, initial + 1);
+    assert_eq!(balance.to, initial + 2);
+
+    // Each transfer pays for its gas at 3 wei, of which the miner earns what exceeds the base fee
+    // of 1 wei.
+    let sender = state_diff[&STUB_SENDER.parse::<Address>().unwrap()]
+        .balance
+        .clone()
+        .unwrap();
+
+    assert_eq!(sender.from, initial - 1 - 21_000 * 3);
+    assert_eq!(sender.to, initial - 2 - 2 * 21_000 * 3);
+
+    let miner = state_diff[&STUB_MINER.parse::<Address>().unwrap()]
+        .balance
+        .clone()
+        .unwrap();
+
+    assert_eq!(miner.from, initial + 21_000 * 2);
+    assert_eq!(miner.to, initial + 2 * 21_000 * 2);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/replay")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "txHash": STUB_TX,
+            "skipPreceding": true,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/simulations/{}", body.simulation_id))
+        .reply(&filter)
+        .await;
+
+    let stored: StoredSimulation = serde_json::from_slice(res.body()).unwrap();
+    let balance = stored.state_diff.unwrap()[&recipient]
+        .balance
+        .clone()
+        .unwrap();
+
+    assert_eq!(balance.from, initial);
+    assert_eq!(balance.to, initial + 1);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/replay")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "txHash": format!("0x{}", "33".repeat(32)),
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "TRANSACTION_NOT_FOUND".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_block() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/replay-block")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "blockNumber": 2,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: ReplayBlockResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.block_number, 2);
+    assert_eq!(body.transactions.len(), 2);
+    assert!(body
+        .transactions
+        .iter()
+        .all(|transaction| transaction.success));
+
+    // The first transfer succeeds, unlike what its receipt claims.
+    let preceding = &body.transactions[0];
+    assert_eq!(preceding.tx_hash, STUB_PRECEDING_TX.parse().unwrap());
+    assert!(preceding.divergences.contains(&Divergence {
+        field: DivergenceField::Status,
+        on_chain: 0,
+        simulated: 1,
+    }));
+    assert!(body.transactions[1]
+        .divergences
+        .iter()
+        .all(|divergence| divergence.field != DivergenceField::Status));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_replay_block_errored_transaction() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/replay-block")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "blockNumber": 3,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: ReplayBlockResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.transactions.len(), 2);
+    assert_eq!(body.divergent, 1);
+
+    // The transaction which couldn't run is reported, and the next one still runs.
+    let errored = &body.transactions[0];
+    assert_eq!(errored.tx_hash, STUB_ERRORED_TX.parse().unwrap());
+    assert!(!errored.success);
+    assert!(errored.error.is_some());
+    assert!(errored.divergences.contains(&Divergence {
+        field: DivergenceField::Status,
+        on_chain: 1,
+        simulated: 0,
+    }));
+
+    let last = &body.transactions[1];
+    assert_eq!(last.tx_hash, STUB_LAST_TX.parse().unwrap());
+    assert!(last.success);
+    assert!(last.error.is_none());
+    assert!(last.divergences.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_token_overrides() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // The layout found for the first holder is reused for the second.
+    for (holder, balance) in [(STUB_SENDER, 1000u64), (STUB_RECIPIENT, 2000u64)] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .json(&serde_json::json!({
+                "chainId": 31337,
+                "from": STUB_SENDER,
+                "to": STUB_TOKEN,
+                "data": format!("0x70a08231{:0>64}", &holder[2..]),
+                "gasLimit": 100000,
+                "tokenOverrides": [{
+                    "token": STUB_TOKEN,
+                    "holder": holder,
+                    "balance": balance.to_string(),
+                }],
+            }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 200);
+
+        let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+        assert!(body.success);
+        assert_eq!(U256::from_big_endian(&body.return_data), balance.into());
+    }
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_TOKEN,
+            "gasLimit": 100000,
+            "tokenOverrides": [{
+                "token": STUB_RECIPIENT,
+                "holder": STUB_SENDER,
+                "balance": "1000",
+            }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "TOKEN_SLOT_NOT_FOUND".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_bundle_mocks() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let ecrecover = "0x0000000000000000000000000000000000000001";
+    let call = |to: &str, data: String, mocks: serde_json::Value| {
+        serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": to,
+            "data": data,
+            "gasLimit": 100000,
+            "mocks": mocks,
+        })
+    };
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([
+            call(
+                ecrecover,
+                format!("0x{}", "00".repeat(128)),
+                serde_json::json!([{ "kind": "ecrecover", "signer": STUB_SENDER }])
+            ),
+            // latestAnswer()
+            call(
+                STUB_RECIPIENT,
+                "0x50d25bcd".to_string(),
+                serde_json::json!([{
+                    "kind": "priceFeed",
+                    "address": STUB_RECIPIENT,
+                    "answer": "200000000000",
+                    "decimals": 8,
+                }])
+            ),
+            call(
+                STUB_RECIPIENT,
+                "0x12345678".to_string(),
+                serde_json::json!([{
+                    "kind": "call",
+                    "address": STUB_RECIPIENT,
+                    "data": "0x12345678",
+                    "returnData": "0xdead",
+                    "revert": true,
+                }])
+            ),
+            // Mocks stay in place for the following transactions.
+            call(
+                ecrecover,
+                format!("0x{}", "11".repeat(128)),
+                serde_json::json!([])
+            ),
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+    let signer = H256::from(STUB_SENDER.parse::<Address>().unwrap());
+
+    assert!(body[0].success);
+    assert_eq!(body[0].return_data, Bytes::from(signer.as_bytes().to_vec()));
+    assert!(body[1].success);
+    assert_eq!(
+        U256::from_big_endian(&body[1].return_data),
+        U256::from(200_000_000_000u64)
+    );
+    assert!(!body[2].success);
+    assert_eq!(body[2].return_data, "0xdead".parse::<Bytes>().unwrap());
+    assert_eq!(body[3].return_data, body[0].return_data);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_bundle_price_feed_timestamp() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // latestTimestamp()
+    let call = |block_number: u64| {
+        serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_RECIPIENT,
+            "data": "0x8205bf6a",
+            "gasLimit": 100000,
+            "blockNumber": block_number,
+            "mocks": [{
+                "kind": "priceFeed",
+                "address": STUB_RECIPIENT,
+                "answer": "200000000000",
+            }],
+        })
+    };
+    let mut later = call(3);
+    later["mocks"] = serde_json::json!([]);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([call(2), later]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    // The feed installed for the first block answers with the timestamp of the next one.
+    assert!(body.iter().all(|simulation| simulation.success));
+    assert_eq!(
+        U256::from_big_endian(&body[1].return_data),
+        U256::from_big_endian(&body[0].return_data) + 12
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_hardfork() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // PUSH0 PUSH0 RETURN, only valid from Shanghai on.
+    let simulate = |hardfork: Option<&str>| {
+        warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .json(&serde_json::json!({
+                "chainId": 31337,
+                "from": STUB_SENDER,
+                "to": STUB_RECIPIENT,
+                "gasLimit": 100000,
+                "stateOverrides": { STUB_RECIPIENT: { "code": "0x5f5ff3" } },
+                "hardfork": hardfork,
+            }))
+            .reply(&filter)
+    };
+
+    // Chains without known hard forks run with the latest one supported.
+    let res = simulate(None).await;
+    assert_eq!(res.status(), 200);
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    assert!(body.success);
+    assert_eq!(body.hardfork, Hardfork::Shanghai);
+
+    let res = simulate(Some("london")).await;
+    assert_eq!(res.status(), 200);
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    assert!(!body.success);
+    assert_eq!(body.hardfork, Hardfork::London);
+
+    let res = simulate(Some("cancun")).await;
+    assert_eq!(res.status(), 400);
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body.message, "HARDFORK_NOT_SUPPORTED".to_string());
+
+    // A transaction of a bundle asking for a hard fork only runs with it itself.
+    let transaction = |hardfork: Option<&str>| {
+        serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_RECIPIENT,
+            "gasLimit": 100000,
+            "stateOverrides": { STUB_RECIPIENT: { "code": "0x5f5ff3" } },
+            "hardfork": hardfork,
+        })
+    };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([
+            transaction(None),
+            transaction(Some("london")),
+            transaction(None),
+        ]))
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 200);
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        body.iter()
+            .map(|simulation| (simulation.success, simulation.hardfork))
+            .collect::<Vec<_>>(),
+        vec![
+            (true, Hardfork::Shanghai),
+            (false, Hardfork::London),
+            (true, Hardfork::Shanghai),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_blob_transaction() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // Either field makes a blob transaction, which would otherwise run without its blobs.
+    for blob_fields in [
+        serde_json::json!({
+            "blobVersionedHashes": [format!("0x01{}", "11".repeat(31))],
+            "maxFeePerBlobGas": "1",
+        }),
+        serde_json::json!({ "maxFeePerBlobGas": "1" }),
+    ] {
+        let mut request = serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_RECIPIENT,
+            "gasLimit": 100000,
+        });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(blob_fields.as_object().unwrap().clone());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/simulate")
+            .json(&request)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(res.status(), 400);
+
+        let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+        assert_eq!(body.message, "BLOB_TRANSACTIONS_NOT_SUPPORTED".to_string());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_permit_mock() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // The stub token answers DOMAIN_SEPARATOR() and nonces() with 100, as every slot it reads.
+    let word = |value: u64| H256::from_low_u64_be(value);
+    let owner: Address = STUB_SENDER.parse().unwrap();
+    let spender: Address = STUB_RECIPIENT.parse().unwrap();
+    let struct_hash = keccak256(ethers::abi::encode(&[
+        Token::FixedBytes(
+            keccak256(
+                "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)",
+            )
+            .to_vec(),
+        ),
+        Token::Address(owner),
+        Token::Address(spender),
+        Token::Uint(1000.into()),
+        Token::Uint(100.into()),
+        Token::Uint(U256::MAX),
+    ]));
+    let digest = keccak256([[0x19, 0x01].as_slice(), word(100).as_bytes(), &struct_hash].concat());
+
+    let signature = [word(27), word(1), word(1)].map(|word| word.0).concat();
+    let ecrecover = |digest: &[u8]| {
+        serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": "0x0000000000000000000000000000000000000001",
+            "data": Bytes::from([digest, signature.as_slice()].concat()),
+            "gasLimit": 100000,
+            "mocks": [{
+                "kind": "permit",
+                "token": STUB_TOKEN,
+                "owner": STUB_SENDER,
+                "spender": STUB_RECIPIENT,
+                "value": "1000",
+                "deadline": format!("{}", U256::MAX),
+            }],
+        })
+    };
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([
+            ecrecover(&digest),
+            ecrecover(H256::zero().as_bytes())
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+    let recovered = Bytes::from(H256::from(owner).as_bytes().to_vec());
+
+    assert_eq!(body[0].return_data, recovered);
+    // Signatures of other digests are still checked.
+    assert_ne!(body[1].return_data, recovered);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_mocks_no_cheatcodes() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // `addr(1)` of the HEVM cheatcodes, from the account tests call them from, along with a mock
+    // so that the cheatcodes inspector is installed.
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "from": "0x1804c8AB1F12E6bbf3894d4083f33e07309d1f38",
+            "to": "0x7109709ECfa91a80626fF3989D68f67F5b1DD12D",
+            "data": format!("0xffa18649{:064x}", 1),
+            "gasLimit": 100000,
+            "mocks": [{ "kind": "ecrecover", "signer": STUB_SENDER }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+    let derived: Address = "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+        .parse()
+        .unwrap();
+
+    assert!(!body.success);
+    assert_ne!(
+        body.return_data,
+        Bytes::from(H256::from(derived).as_bytes().to_vec())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_safe_not_a_safe() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-safe")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_RECIPIENT,
+            "to": STUB_TOKEN,
+            "data": "0x12345678",
+            "gasLimit": 500000,
+            "operation": 1,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INVALID_SAFE".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_safe() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    // The Safe checks the balance of its owner, which only passes its threshold of 2 once it's
+    // lowered to 1.
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-safe")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SAFE,
+            "to": STUB_TOKEN,
+            "data": format!("0x70a08231{:0>64}", &STUB_SENDER[2..]),
+            "gasLimit": 500000,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.success);
+    // What the call of the Safe returned, rather than what `execTransaction` did.
+    assert_eq!(U256::from_big_endian(&body.return_data), 100.into());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_user_operation_not_an_entry_point() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-user-operation")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "entryPoint": STUB_RECIPIENT,
+            "userOperation": {
+                "sender": STUB_SENDER,
+                "nonce": "0x0",
+                "callData": "0x",
+                "callGasLimit": "0x186a0",
+                "verificationGasLimit": "0x186a0",
+                "preVerificationGas": "0xc350",
+                "maxFeePerGas": "0x0",
+                "maxPriorityFeePerGas": "0x0",
+                "signature": "0x",
+            },
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INVALID_ENTRY_POINT".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_user_operation() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-user-operation")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "entryPoint": STUB_ENTRY_POINT,
+            "userOperation": {
+                "sender": STUB_SENDER,
+                "nonce": "0x0",
+                "callData": "0x",
+                "callGasLimit": "0x186a0",
+                "verificationGasLimit": "0x186a0",
+                "preVerificationGas": "0xc350",
+                "maxFeePerGas": "0x0",
+                "maxPriorityFeePerGas": "0x0",
+                "signature": "0x",
+            },
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: UserOperationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.failure, None);
+
+    let validation = body.validation.unwrap();
+    assert_eq!(validation.pre_op_gas, 80_000.into());
+    assert_eq!(validation.valid_until, u64::from(u32::MAX));
+
+    assert!(body.simulation.unwrap().success);
+
+    let execution = body.execution.unwrap();
+    assert_eq!(execution.user_op_hash, H256::repeat_byte(0x66));
+    assert!(execution.success);
+    assert_eq!(execution.actual_gas_used, 150_000.into());
+    assert_eq!(execution.gas.pre_verification, 50_000.into());
+    assert_eq!(execution.gas.verification, 30_000.into());
+    assert_eq!(execution.gas.call, 70_000.into());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_symbolic_slots() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let request = |key: &str| {
+        serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_TOKEN,
+            "data": format!("0x70a08231{:0>64}", &STUB_SENDER[2..]),
+            "gasLimit": 100000,
+            "stateOverrides": {
+                STUB_TOKEN: {
+                    "slots": [{
+                        "slot": "0",
+                        "path": [{ "key": key, "keyType": "address" }],
+                        "value": "1000",
+                    }],
+                },
+            },
+        })
+    };
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request(STUB_SENDER))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: SimulationResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(U256::from_big_endian(&body.return_data), 1000.into());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&request("0x1234"))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INVALID_STORAGE_SLOT".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_stateful_state() {
+    let config = Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    };
+    let state = Arc::new(SharedSimulationState::new(&config));
+    let filter = warp::any()
+        .and(simulate_routes(config.clone(), state.clone()))
+        .recover(handle_rejection);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-stateful")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "gasLimit": 500000,
+            "blockNumber": 2,
+        }))
+        .reply(&filter)
+        .await;
+
+    let id = serde_json::from_slice::<StatefulSimulationResponse>(res.body())
+        .unwrap()
+        .stateful_simulation_id;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}/state"))
+        .json(&serde_json::json!({
+            STUB_SENDER: { "balance": "5", "nonce": 7 },
+            STUB_TOKEN: {
+                "slots": [{
+                    "slot": "0",
+                    "path": [{ "key": STUB_SENDER, "keyType": "address" }],
+                    "value": "1000",
+                }],
+            },
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: std::collections::BTreeMap<Address, AccountState> =
+        serde_json::from_slice(res.body()).unwrap();
+
+    let sender = &body[&STUB_SENDER.parse::<Address>().unwrap()];
+    assert_eq!(sender.balance, 5.into());
+    assert_eq!(sender.nonce, 7);
+    assert!(sender.storage.is_empty());
+
+    let token = &body[&STUB_TOKEN.parse::<Address>().unwrap()];
+    assert_eq!(token.code, STUB_TOKEN_CODE.parse::<Bytes>().unwrap());
+    assert_eq!(
+        token.storage.values().copied().collect::<Vec<_>>(),
+        vec![U256::from(1000)]
+    );
+
+    // Overrides are applied all together or not at all.
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}/state"))
+        .json(&serde_json::json!({
+            STUB_SENDER: { "balance": "9" },
+            STUB_TOKEN: {
+                "slots": [{ "slot": "1", "size": 0, "value": "1" }],
+            },
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INVALID_STORAGE_SLOT".to_string());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}/state"))
+        .json(&serde_json::json!({ STUB_SENDER: {} }))
+        .reply(&filter)
+        .await;
+
+    let body: std::collections::BTreeMap<Address, AccountState> =
+        serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(
+        body[&STUB_SENDER.parse::<Address>().unwrap()].balance,
+        5.into()
+    );
+
+    // Nor are the state overrides of a transaction whose token overrides fail.
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}"))
+        .json(&serde_json::json!([{
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_RECIPIENT,
+            "gasLimit": 100000,
+            "stateOverrides": { STUB_SENDER: { "balance": "9" } },
+            "tokenOverrides": [{
+                "token": STUB_RECIPIENT,
+                "holder": STUB_SENDER,
+                "balance": "1000",
+            }],
+        }]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}/state"))
+        .json(&serde_json::json!({ STUB_SENDER: {} }))
+        .reply(&filter)
+        .await;
+
+    let body: std::collections::BTreeMap<Address, AccountState> =
+        serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(
+        body[&STUB_SENDER.parse::<Address>().unwrap()].balance,
+        5.into()
+    );
+
+    // The overrides are kept when the session is saved and restored.
+    let path = std::env::temp_dir().join(format!("temper-sessions-{id}.json"));
+    assert_eq!(sessions::save(&path, &state).unwrap(), 1);
+
+    let restored_state = Arc::new(SharedSimulationState::new(&config));
+    assert_eq!(
+        sessions::restore(&path, &config, &restored_state)
+            .await
+            .unwrap(),
+        1
+    );
+    let filter = warp::any()
+        .and(simulate_routes(config, restored_state))
+        .recover(handle_rejection);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!("/simulate-stateful/{id}"))
+        .json(&serde_json::json!([{
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_TOKEN,
+            "data": format!("0x70a08231{:0>64}", &STUB_SENDER[2..]),
+            "gasLimit": 100000,
+            "blockNumber": 2,
+        }]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(U256::from_big_endian(&body[0].return_data), 1000.into());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_bundle_full_and_diff_state() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let balance_slot = |holder: &str| {
+        let holder: Address = holder.parse().unwrap();
+        let preimage = [H256::from(holder).as_bytes(), H256::zero().as_bytes()].concat();
+        format!("{:?}", H256(keccak256(preimage)))
+    };
+    let balance_of = |holder: &str, overrides: serde_json::Value| {
+        serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_TOKEN,
+            "data": format!("0x70a08231{:0>64}", &holder[2..]),
+            "gasLimit": 100000,
+            "stateOverrides": overrides,
+        })
+    };
+    let recipient_slot = balance_slot(STUB_RECIPIENT);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([
+            // A diff keeps the other slots.
+            balance_of(
+                STUB_SENDER,
+                serde_json::json!({ STUB_TOKEN: { "stateDiff": { &recipient_slot: "0x7" } } })
+            ),
+            // A full override clears them.
+            balance_of(
+                STUB_SENDER,
+                serde_json::json!({ STUB_TOKEN: { "state": { &recipient_slot: "0x7" } } })
+            ),
+            // Both last for the following transactions.
+            balance_of(STUB_RECIPIENT, serde_json::json!({})),
+            balance_of(STUB_SENDER, serde_json::json!({})),
+        ]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: Vec<SimulationResponse> = serde_json::from_slice(res.body()).unwrap();
+    let balances: Vec<U256> = body
+        .iter()
+        .map(|simulation| U256::from_big_endian(&simulation.return_data))
+        .collect();
+
+    assert_eq!(balances, [100u64, 0, 7, 0].map(U256::from).to_vec());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([balance_of(
+            STUB_SENDER,
+            serde_json::json!({ STUB_TOKEN: {
+                "state": { &recipient_slot: "0x7" },
+                "stateDiff": { &recipient_slot: "0x7" },
+            } })
+        )]))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-bundle")
+        .json(&serde_json::json!([balance_of(
+            STUB_SENDER,
+            serde_json::json!({ "0x0000000000000000000000000000000000000001": {
+                "movePrecompileToAddress": STUB_RECIPIENT,
+            } })
+        )]))
+        .reply(&filter)
+        .await;
+
+    // Moving precompiles isn't supported, and rejected rather than ignored.
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body
+        .message
+        .contains("unknown field `movePrecompileToAddress`"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_in_block() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let transaction = serde_json::json!({
+        "chainId": 31337,
+        "from": STUB_RECIPIENT,
+        "to": STUB_SENDER,
+        "gasLimit": 21000,
+        "value": "1",
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-in-block")
+        .json(&serde_json::json!({
+            "blockNumber": 2,
+            "index": 1,
+            "transaction": transaction,
+            "replayRemaining": true,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: InsertResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.simulation.success);
+    assert_eq!(body.simulation.block_number, 2);
+    assert_eq!(body.remaining.len(), 1);
+    assert_eq!(body.remaining[0].tx_hash, STUB_TX.parse().unwrap());
+
+    // It was committed for the remaining transactions to see, so its state diff isn't known and
+    // can't be compared with that of a rerun.
+    let res = warp::test::request()
+        .method("POST")
+        .path(&format!(
+            "/simulations/{}/rerun",
+            body.simulation.simulation_id
+        ))
+        .json(&serde_json::json!({ "blockNumber": 1 }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let rerun: RerunResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(rerun.comparison.state_diff.is_none());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate-in-block")
+        .json(&serde_json::json!({
+            "blockNumber": 2,
+            "index": 3,
+            "transaction": transaction,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 400);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "INVALID_TRANSACTION_INDEX".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_call_bundle() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+        .parse::<LocalWallet>()
+        .unwrap()
+        .with_chain_id(31337u64);
+    let sign = |to: &str, nonce: u64| {
+        let transaction: TypedTransaction = TransactionRequest::new()
+            .to(to.parse::<Address>().unwrap())
+            .value(1)
+            .gas(21_000)
+            .gas_price(2_000_000_000u64)
+            .nonce(nonce)
+            .chain_id(31337)
+            .into();
+        let signature = wallet.sign_transaction_sync(&transaction).unwrap();
+        transaction.rlp_signed(&signature)
+    };
+    // The second transfer pays the miner of the stub blocks directly.
+    let txs = vec![sign(STUB_RECIPIENT, 0), sign(STUB_MINER, 1)];
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/rpc/31337")
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_callBundle",
+            "params": [{
+                "txs": txs,
+                "blockNumber": "0x3",
+                "stateBlockNumber": "latest",
+            }],
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: JsonRpcResponse<CallBundleResponse> = serde_json::from_slice(res.body()).unwrap();
+    let result = body.result.unwrap();
+
+    assert_eq!(body.id, serde_json::json!(1));
+    assert_eq!(result.state_block_number, 2);
+    assert_eq!(result.results.len(), 2);
+    assert!(result
+        .results
+        .iter()
+        .all(|result| result.from_address == wallet.address() && result.error.is_none()));
+    assert_eq!(result.total_gas_used, 42_000);
+    // The base fee of 1 wei is burnt rather than paid to the miner.
+    assert_eq!(
+        result.gas_fees,
+        (42_000 * (2_000_000_000u64 - 1)).to_string()
+    );
+    assert_eq!(result.eth_sent_to_coinbase, "1");
+    assert_eq!(
+        result.coinbase_diff,
+        (42_000 * (2_000_000_000u64 - 1) + 1).to_string()
+    );
+
+    let hashes: Vec<u8> = txs.iter().flat_map(|tx| keccak256(tx).to_vec()).collect();
+    assert_eq!(result.bundle_hash, H256(keccak256(hashes)));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/rpc/31337")
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "eth_callBundle",
+            "params": [{
+                "txs": ["0x02"],
+                "blockNumber": "0x3",
+                "stateBlockNumber": "latest",
+            }],
+        }))
+        .reply(&filter)
+        .await;
+
+    let body: JsonRpcResponse<CallBundleResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.error.unwrap().code, -32602);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/rpc/31337")
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "eth_sendBundle",
+            "params": [],
+        }))
+        .reply(&filter)
+        .await;
+
+    let body: JsonRpcResponse<CallBundleResponse> = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.error.unwrap().code, -32601);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_call_bundle_block_params() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+        .parse::<LocalWallet>()
+        .unwrap();
+    let sign = |to: &str, nonce: u64, data: &str, chain_id: u64| {
+        let transaction: TypedTransaction = TransactionRequest::new()
+            .to(to.parse::<Address>().unwrap())
+            .data(data.parse::<Bytes>().unwrap())
+            .gas(100_000)
+            .gas_price(2_000_000_000u64)
+            .nonce(nonce)
+            .chain_id(chain_id)
+            .into();
+        let signature = wallet
+            .clone()
+            .with_chain_id(chain_id)
+            .sign_transaction_sync(&transaction)
+            .unwrap();
+        transaction.rlp_signed(&signature)
+    };
+    let call_bundle = |params: serde_json::Value| {
+        warp::test::request()
+            .method("POST")
+            .path("/rpc/31337")
+            .json(&serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "eth_callBundle",
+                "params": [params],
+            }))
+            .reply(&filter)
+    };
+
+    // simulateValidation(...), which always reverts with its result.
+    let res = call_bundle(serde_json::json!({
+        "txs": [sign(STUB_ENTRY_POINT, 0, "0xee219423", 31337)],
+        "blockNumber": "0x3",
+        "stateBlockNumber": "latest",
+        "coinbase": STUB_RECIPIENT,
+        "baseFee": "0x0",
+        "gasLimit": 50_000_000,
+    }))
+    .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: JsonRpcResponse<CallBundleResponse> = serde_json::from_slice(res.body()).unwrap();
+    let result = body.result.unwrap();
+    let reverted = &result.results[0];
+
+    assert_eq!(reverted.error, Some("execution reverted".to_string()));
+    assert!(reverted.revert.as_ref().unwrap().starts_with("0xe0cff05f"));
+    assert_eq!(reverted.value, None);
+    // Without a base fee, the whole gas price is paid to the coinbase given.
+    assert_eq!(
+        result.coinbase_diff,
+        (reverted.gas_used * 2_000_000_000u64).to_string()
+    );
+    assert_eq!(result.eth_sent_to_coinbase, "0");
+
+    // Nonces and chain IDs are those of the fork, and unsupported parameters aren't ignored.
+    for (params, code) in [
+        (
+            serde_json::json!({
+                "txs": [sign(STUB_RECIPIENT, 1, "0x", 31337)],
+                "blockNumber": "0x3",
+                "stateBlockNumber": "latest",
+            }),
+            -32000,
+        ),
+        (
+            serde_json::json!({
+                "txs": [sign(STUB_RECIPIENT, 0, "0x", 1)],
+                "blockNumber": "0x3",
+                "stateBlockNumber": "latest",
+            }),
+            -32602,
+        ),
+        (
+            serde_json::json!({
+                "txs": [sign(STUB_RECIPIENT, 0, "0x", 31337)],
+                "blockNumber": "0x3",
+                "stateBlockNumber": "latest",
+                "timeout": 5,
+            }),
+            -32602,
+        ),
+    ] {
+        let res = call_bundle(params).await;
+
+        assert_eq!(res.status(), 200);
+
+        let body: JsonRpcResponse<CallBundleResponse> = serde_json::from_slice(res.body()).unwrap();
+
+        assert_eq!(body.error.unwrap().code, code);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn options_simulate_cors_preflight() {
+    let config = Config {
+        cors_origins: vec!["https://app.example".to_string()],
+        ..config()
+    };
+    let shared_state = Arc::new(SharedSimulationState::new(&config));
+    let filter = simulate_routes(config.clone(), shared_state)
+        .with(cors(&config).unwrap())
+        .recover(handle_rejection);
+
+    let res = warp::test::request()
+        .method("OPTIONS")
+        .path("/simulate")
+        .header("Origin", "https://app.example")
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type, x-api-key")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers()["access-control-allow-origin"],
+        "https://app.example"
+    );
+
+    let res = warp::test::request()
+        .method("OPTIONS")
+        .path("/simulate")
+        .header("Origin", "https://other.example")
+        .header("Access-Control-Request-Method", "POST")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 403);
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "CORS_FORBIDDEN".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_simulate_cors_rejected() {
+    let config = Config {
+        cors_origins: vec!["https://app.example".to_string()],
+        max_concurrent_simulations: 0,
+        max_queued_simulations: 0,
+        ..config()
+    };
+    let shared_state = Arc::new(SharedSimulationState::new(&config));
+    let filter = with_request_id(simulate_routes(config.clone(), shared_state))
+        .with(cors(&config).unwrap())
+        .recover(handle_rejection);
+
+    let json = serde_json::json!({
+      "chainId": 1,
+      "from": "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045",
+      "to": "0x66fc62c1748e45435b06cf8dd105b73e9855f93e",
+      "gasLimit": 21000,
+      "value": "100000",
+      "blockNumber": 16784600
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .header("Origin", "https://app.example")
+        .json(&json)
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 429);
+    assert_eq!(
+        res.headers()["access-control-allow-origin"],
+        "https://app.example"
+    );
+
+    let exposed = res.headers()["access-control-expose-headers"]
+        .to_str()
+        .unwrap()
+        .to_lowercase();
+
+    assert!(exposed.contains("retry-after"));
+    assert!(exposed.contains("x-request-id"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_unknown_request_id() {
+    let config = config();
+    let shared_state = Arc::new(SharedSimulationState::new(&config));
+    let filter = with_request_id(simulate_routes(config, shared_state));
+
+    let res = warp::test::request()
+        .path("/unknown")
+        .header("X-Request-Id", "some-request-id")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 404);
+    assert_eq!(res.headers()["x-request-id"], "some-request-id");
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.message, "NOT_FOUND".to_string());
+    assert_eq!(body.request_id, Some("some-request-id".to_string()));
+
+    let res = warp::test::request().path("/unknown").reply(&filter).await;
+
+    let body: ErrorMessage = serde_json::from_slice(res.body()).unwrap();
+    let request_id = res.headers()["x-request-id"].to_str().unwrap();
+
+    assert!(uuid::Uuid::parse_str(request_id).is_ok());
+    assert_eq!(body.request_id, Some(request_id.to_string()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_health() {
+    let res = warp::test::request()
+        .method("GET")
+        .path("/health")
+        .reply(&health())
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: HealthResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert_eq!(body.status, "ok".to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_metrics() {
+    let filter = filter(Config {
+        fork_url: Some(stub_rpc().await),
+        fork_cache: false,
+        ..config()
+    });
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/simulate")
+        .json(&serde_json::json!({
+            "chainId": 31337,
+            "from": STUB_SENDER,
+            "to": STUB_RECIPIENT,
+            "gasLimit": 100000,
+        }))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/metrics")
+        .reply(&metrics())
+        .await;
+
+    assert_eq!(res.status(), 200);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+
+    let body = String::from_utf8(res.body().to_vec()).unwrap();
+
+    assert!(body.contains(r#"temper_simulations_total{chain_id="31337",outcome="success"}"#));
+    assert!(body.contains(r#"temper_forks_total{chain_id="31337",outcome="success"}"#));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_ready() {
+    let config = Config {
+        fork_url: Some("https://eth.llamarpc.com".to_string()),
+        ..config()
+    };
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/ready")
+        .reply(&ready(config))
+        .await;
+
+    assert_eq!(res.status(), 200);
+
+    let body: ReadinessResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(body.ready);
+    assert_eq!(body.chains.len(), 1);
+    assert_eq!(body.chains[0].chain_id, Some(1));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_ready_unreachable_upstream() {
+    let config = Config {
+        fork_url: Some("http://10.255.255.1".to_string()),
+        fork_timeout: 1,
+        ..config()
+    };
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/ready")
+        .reply(&ready(config))
+        .await;
+
+    assert_eq!(res.status(), 503);
+
+    let body: ReadinessResponse = serde_json::from_slice(res.body()).unwrap();
+
+    assert!(!body.ready);
+    assert_eq!(body.chains[0].error, Some("UPSTREAM_TIMEOUT".to_string()));
+}